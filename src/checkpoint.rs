@@ -0,0 +1,103 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// Serials are unique across the process, so that a checkpoint handed to
+/// the wrong map is rejected rather than closing one of its own.
+static NEXT_SERIAL: AtomicU64 = AtomicU64::new(0);
+
+/// A point a map can be rolled back to, returned by `checkpoint` and closed
+/// by either `commit` or `rollback`.
+///
+/// Dropping a checkpoint does not close it: the map keeps logging changes,
+/// and refusing to `drain`, `compact` or shrink, until it is closed along
+/// with an enclosing checkpoint. A checkpoint dropped with none open around
+/// it leaves the log open for the life of the map.
+#[must_use = "a checkpoint stays open until it is committed or rolled back"]
+#[derive(Debug, PartialEq, Eq)]
+pub struct Checkpoint {
+    serial: u64,
+}
+
+/// A change made to a `CompactIdMap` while a checkpoint is open, holding
+/// what is needed to undo it.
+#[derive(Clone, Debug)]
+pub(crate) enum Change<I, K> {
+    /// `id` was inserted, either taken from the recycle bin or minted from
+    /// `next_new_id`, when the map had `slots` slots.
+    Insert { id: I, recycled: bool, slots: usize },
+    /// `id` was removed, holding `k`, when the map tracked `generations`
+    /// slot generations.
+    Remove { id: I, k: K, generations: usize },
+    /// The value of `id` was replaced; `k` is the old one.
+    Replace { id: I, k: K },
+}
+
+/// The changes made since the outermost open checkpoint. Not `Clone`: the
+/// checkpoints it tracks belong to one map.
+#[derive(Debug)]
+pub(crate) struct UndoLog<I, K> {
+    changes: Vec<Change<I, K>>,
+    /// The serial of each open checkpoint and the number of changes logged
+    /// when it was opened, innermost last.
+    open: Vec<(u64, usize)>,
+    /// Copies keys that are handed back to the caller but must be restored
+    /// on rollback. Only checkpoints can set it, and they require `K: Clone`.
+    copy_key: Option<fn(&K) -> K>,
+}
+
+impl<I, K> Default for UndoLog<I, K> {
+    fn default() -> Self {
+        Self {
+            changes: Vec::new(),
+            open: Vec::new(),
+            copy_key: None,
+        }
+    }
+}
+
+impl<I, K> UndoLog<I, K> {
+    pub(crate) fn is_open(&self) -> bool {
+        !self.open.is_empty()
+    }
+
+    pub(crate) fn open(&mut self, copy_key: fn(&K) -> K) -> Checkpoint {
+        let serial = NEXT_SERIAL.fetch_add(1, Ordering::Relaxed);
+        self.open.push((serial, self.changes.len()));
+        self.copy_key = Some(copy_key);
+        Checkpoint { serial }
+    }
+
+    /// Logs the change built by `change` if a checkpoint is open.
+    pub(crate) fn record<F>(&mut self, change: F)
+    where
+        F: FnOnce(fn(&K) -> K) -> Change<I, K>
+    {
+        if let (true, Some(copy_key)) = (self.is_open(), self.copy_key) {
+            self.changes.push(change(copy_key));
+        }
+    }
+
+    /// Closes `checkpoint` and every checkpoint opened after it, returning
+    /// the number of changes logged before it.
+    fn close(&mut self, checkpoint: Checkpoint) -> usize {
+        let depth = self.open.iter().position(|&(serial, _)| serial == checkpoint.serial)
+            .expect("checkpoint of another map, or already closed");
+        let (_, logged) = self.open[depth];
+        self.open.truncate(depth);
+        logged
+    }
+
+    /// Keeps the changes made since `checkpoint`, which an enclosing
+    /// checkpoint can still roll back.
+    pub(crate) fn commit(&mut self, checkpoint: Checkpoint) {
+        self.close(checkpoint);
+        if !self.is_open() {
+            self.changes.clear();
+        }
+    }
+
+    /// Returns the changes made since `checkpoint`, most recent last.
+    pub(crate) fn rollback(&mut self, checkpoint: Checkpoint) -> Vec<Change<I, K>> {
+        let logged = self.close(checkpoint);
+        self.changes.split_off(logged)
+    }
+}
@@ -1,145 +1,1293 @@
-use std::{borrow::Borrow, fmt::Debug, hash::Hash};
+use std::{
+    borrow::Borrow,
+    collections::VecDeque,
+    hash::{BuildHasher, Hash},
+    marker::PhantomData,
+};
 
-use hashbrown::HashMap;
-use increment::Incrementable;
-use serde::{Deserialize, Serialize};
+use hashbrown::{hash_table, DefaultHashBuilder, HashSet, HashTable};
+use serde::{
+    de::{DeserializeSeed, Error as _}, ser::SerializeStruct, Deserialize, Deserializer, Serialize,
+    Serializer,
+};
+
+use crate::{
+    checkpoint::{Change, UndoLog},
+    policy::RecycleBin,
+    Checkpoint, CompactId, Drain, Entry, Error, IdMapOptions, IdRemap, IntoIter, Iter, IterMut,
+    OccupiedEntry, RecyclePolicy, VacantEntry, VacantIdEntry,
+};
 
 pub type ID = usize;
 
-#[derive(Clone, Debug, Serialize, Deserialize)]
-pub struct CompactIdBiMap<K>
+/// Stores `k` in slot `index`, growing the slot vector if the index has never
+/// been handed out before.
+fn fill_slot<K>(slots: &mut Vec<Option<K>>, index: usize, k: K) {
+    if index >= slots.len() {
+        slots.resize_with(index + 1, || None);
+    }
+    debug_assert!(slots[index].is_none());
+    slots[index] = Some(k);
+}
+
+/// What a `CompactIdBiMap` stores in each slot: the key alone, or the key
+/// together with a value as in a `CompactIdValueMap`.
+pub trait Slot<K> {
+    fn key(&self) -> &K;
+
+    /// The key, for replacing it while the bimap keeps its index in sync.
+    fn key_mut(&mut self) -> &mut K;
+}
+
+impl<K> Slot<K> for K {
+    fn key(&self) -> &K {
+        self
+    }
+
+    fn key_mut(&mut self) -> &mut K {
+        self
+    }
+}
+
+/// A bidirectional map between keys and compact IDs.
+///
+/// Keys are hashed with `S`, hashbrown's default hasher unless another
+/// `BuildHasher` is given, e.g. a fixed-seed one for reproducible builds.
+///
+/// Each slot holds a `T`, by default just the key. `CompactIdValueMap` pins
+/// it to a `(key, value)` pair.
+#[derive(Clone, Debug)]
+pub struct CompactIdBiMap<K, I = ID, S = DefaultHashBuilder, T = K>
     where K: Eq + Hash
 {
-    ids: HashMap<K, ID>,
-    keys: HashMap<ID, K>,
-    recycle_bin: Vec<ID>,
-    next_new_id: ID,
+    map: CompactIdMap<I, T>,
+    /// Reverse index holding only IDs; entries are hashed through the key
+    /// stored in the corresponding slot of `map`.
+    ids: HashTable<I>,
+    hash_builder: S,
+    _keys: PhantomData<fn() -> K>,
 }
 
-impl<K> CompactIdBiMap<K> where
-K: Hash + Eq
+impl<K, I, S, T> Default for CompactIdBiMap<K, I, S, T> where
+K: Hash + Eq,
+I: CompactId + Eq,
+S: BuildHasher + Default,
+T: Slot<K>
+{
+    fn default() -> Self {
+        Self::with_hasher(S::default())
+    }
+}
+
+impl<K, I> CompactIdBiMap<K, I> where
+K: Hash + Eq,
+I: CompactId + Eq
 {
     pub fn new() -> Self {
+        Self::with_options(IdMapOptions::new())
+    }
+
+    /// An empty bimap with all of the given settings.
+    pub fn with_options(options: IdMapOptions<I>) -> Self {
+        Self::with_options_and_hasher(options, DefaultHashBuilder::default())
+    }
+
+    /// An empty bimap with room for `capacity` keys before reallocating.
+    pub fn with_capacity(capacity: usize) -> Self {
+        Self::with_options(IdMapOptions::new().capacity(capacity))
+    }
+
+    pub fn with_policy(policy: RecyclePolicy) -> Self {
+        Self::with_options(IdMapOptions::new().policy(policy))
+    }
+
+    /// A bimap that never hands out an ID above `max_id`, while still
+    /// reusing freed IDs below it.
+    pub fn with_max_id(max_id: I) -> Self {
+        Self::with_options(IdMapOptions::new().max_id(max_id))
+    }
+}
+
+impl<K, I, S, T> CompactIdBiMap<K, I, S, T> where
+K: Hash + Eq,
+I: CompactId + Eq,
+S: BuildHasher,
+T: Slot<K>
+{
+    /// An empty bimap hashing its keys with `hash_builder`.
+    pub fn with_hasher(hash_builder: S) -> Self {
+        Self::with_capacity_and_hasher(0, hash_builder)
+    }
+
+    /// An empty bimap with room for `capacity` keys, hashing them with
+    /// `hash_builder`.
+    pub fn with_capacity_and_hasher(capacity: usize, hash_builder: S) -> Self {
+        Self::with_options_and_hasher(IdMapOptions::new().capacity(capacity), hash_builder)
+    }
+
+    /// An empty bimap with the given settings, hashing keys with
+    /// `hash_builder`.
+    pub fn with_options_and_hasher(options: IdMapOptions<I>, hash_builder: S) -> Self {
         Self {
-            ids: HashMap::new(),
-            keys: HashMap::new(),
-            recycle_bin: Vec::new(),
-            next_new_id: 0,
+            map: CompactIdMap::with_options(options),
+            ids: HashTable::with_capacity(options.capacity),
+            hash_builder,
+            _keys: PhantomData,
         }
     }
 
-    pub fn get_or_insert(&mut self, k: K) -> ID
+    pub fn with_policy_and_hasher(policy: RecyclePolicy, hash_builder: S) -> Self {
+        Self::with_options_and_hasher(IdMapOptions::new().policy(policy), hash_builder)
+    }
+
+    /// A bimap that never hands out an ID above `max_id`, hashing keys with
+    /// `hash_builder`.
+    pub fn with_max_id_and_hasher(max_id: I, hash_builder: S) -> Self {
+        Self::with_options_and_hasher(IdMapOptions::new().max_id(max_id), hash_builder)
+    }
+
+    pub fn hasher(&self) -> &S {
+        &self.hash_builder
+    }
+
+    /// The underlying map from IDs to slots.
+    pub fn as_map(&self) -> &CompactIdMap<I, T> {
+        &self.map
+    }
+
+    /// Drops the reverse index, keeping the IDs and the allocator state.
+    pub fn into_map(self) -> CompactIdMap<I, T> {
+        self.map
+    }
+
+    /// The slots, for changing what they hold besides the key. Callers must
+    /// leave the keys untouched, as the reverse index hashes them.
+    pub(crate) fn map_mut(&mut self) -> &mut CompactIdMap<I, T> {
+        &mut self.map
+    }
+
+    pub fn policy(&self) -> RecyclePolicy {
+        self.map.policy()
+    }
+
+    pub fn max_id(&self) -> Option<I> {
+        self.map.max_id()
+    }
+
+    /// The equality check of the reverse index: does the ID refer to `key`?
+    fn matches<'a, Q>(map: &'a CompactIdMap<I, T>, key: &'a Q) -> impl Fn(&I) -> bool + 'a
     where
-        K: Clone + Debug,
+        K: Borrow<Q>,
+        Q: ?Sized + Eq
     {
-        self.get(&k).unwrap_or_else(||self.insert(k))
+        move |&id| map.occupied(id).key().borrow() == key
+    }
+
+    /// Upgrades `map` into a bimap hashing its keys with `hash_builder`, like
+    /// the `TryFrom` impl does with `S::default()`. Fails with
+    /// `Error::DuplicateKey` if two slots hold the same key.
+    pub fn from_map_with_hasher(map: CompactIdMap<I, T>, hash_builder: S) -> Result<Self, Error> {
+        let mut bimap = Self {
+            map,
+            ids: HashTable::new(),
+            hash_builder,
+            _keys: PhantomData,
+        };
+        let Self { map, ids, hash_builder, .. } = &mut bimap;
+        for (index, slot) in map.keys.iter().enumerate() {
+            let Some(slot) = slot else { continue };
+            let id = I::from_index(index).ok_or(Error::IdOutOfRange { index })?;
+            let id = id.with_generation(generation_of(&map.generations, index));
+            let k = slot.key();
+            let hash = hash_builder.hash_one(k);
+            match ids.entry(hash, Self::matches(map, k), |&other| {
+                hash_builder.hash_one(map.occupied(other).key())
+            }) {
+                hash_table::Entry::Occupied(_) => return Err(Error::DuplicateKey { index }),
+                hash_table::Entry::Vacant(entry) => {
+                    entry.insert(id);
+                }
+            }
+        }
+        Ok(bimap)
     }
 
-    pub fn get<Q: ?Sized>(&self, key: &Q) -> Option<ID>
+    /// Checks the invariants of the underlying `CompactIdMap` (see
+    /// `CompactIdMap::validate`) and that every key maps back to its ID.
+    pub fn validate(&self) -> Result<(), Error> {
+        self.map.validate()?;
+        for (id, slot) in self.map.iter() {
+            if self.get(slot.key()) != Some(id) {
+                return Err(Error::DuplicateKey { index: id.to_index().ok_or(Error::InvalidId)? });
+            }
+        }
+        Ok(())
+    }
+
+    /// Looks up `k` with a single hash computation, returning an entry that
+    /// can insert it without hashing again.
+    pub fn entry(&mut self, k: K) -> Entry<'_, K, I, T> {
+        match self.lookup(&k) {
+            (hash_table::Entry::Occupied(index), map) => {
+                Entry::Occupied(OccupiedEntry { index, map, _key: PhantomData })
+            }
+            (hash_table::Entry::Vacant(index), map) => Entry::Vacant(VacantEntry { index, map, key: k }),
+        }
+    }
+
+    pub fn get<Q>(&self, key: &Q) -> Option<I>
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Hash + Eq
+    {
+        let hash = self.hash_builder.hash_one(key);
+        self.ids.find(hash, Self::matches(&self.map, key)).copied()
+    }
+
+    /// Hashes `key` once and finds its place in the reverse index. The slots
+    /// are handed back alongside, so that a vacant place can be filled.
+    pub(crate) fn lookup<Q>(&mut self, key: &Q) -> (hash_table::Entry<'_, I>, &mut CompactIdMap<I, T>)
     where
         K: Borrow<Q>,
-        Q: Hash + Eq + Debug
+        Q: ?Sized + Hash + Eq
     {
-        self.ids.get(key).copied()
+        let hash = self.hash_builder.hash_one(key);
+        let Self { map, ids, hash_builder, .. } = self;
+        let entry = ids.entry(hash, Self::matches(map, key), |&id| {
+            hash_builder.hash_one(map.occupied(id).key())
+        });
+        (entry, map)
+    }
+
+    /// Indexes slot `id`, just filled with a key that is not indexed yet.
+    fn index_new(&mut self, hash: u64, id: I) {
+        let Self { map, ids, hash_builder, .. } = self;
+        ids.insert_unique(hash, id, |&other| hash_builder.hash_one(map.occupied(other).key()));
     }
 
-    pub fn get_key(&self, id: ID) -> Option<&K> {
-        self.keys.get(&id)
+    pub fn get_key(&self, id: I) -> Option<&K> {
+        self.map.get(id).map(T::key)
     }
 
-    pub fn insert(&mut self, k: K) -> ID
+    pub fn contains_id(&self, id: I) -> bool {
+        self.map.contains_id(id)
+    }
+
+    /// Replaces the key of `id` with `k`, keeping the reverse index in sync,
+    /// and returns the old key. Keys cannot be mutated in place, as that
+    /// would change their hash behind the index's back.
+    ///
+    /// Fails, handing `k` back, if `id` is free or `k` is the key of another
+    /// ID.
+    pub fn replace_key(&mut self, id: I, k: K) -> Result<K, K> {
+        if !self.contains_id(id) || self.get(&k).is_some_and(|other| other != id) {
+            return Err(k);
+        }
+        let old_hash = self.hash_builder.hash_one(self.map.occupied(id).key());
+        if let Ok(entry) = self.ids.find_entry(old_hash, |&other| other == id) {
+            entry.remove();
+        }
+        let hash = self.hash_builder.hash_one(&k);
+        let old = self.map.update(id, |slot| std::mem::replace(slot.key_mut(), k));
+        self.index_new(hash, id);
+        Ok(old)
+    }
+
+    /// Removes `k` and returns the ID it had.
+    pub fn remove<Q>(&mut self, k: &Q) -> Option<I>
     where
-        K: Clone,
+        K: Borrow<Q>,
+        Q: ?Sized + Hash + Eq
     {
-        let id = self.get_fresh_id();
-        debug_assert!(!self.ids.contains_key(&k));
-        self.ids.insert(k.clone(), id);
-        self.keys.insert(id, k);
-        id
+        self.remove_entry(k).map(|(id, _)| id)
+    }
+
+    /// Removes `k`, returning the ID it had and the slot it was stored in.
+    pub fn remove_entry<Q>(&mut self, k: &Q) -> Option<(I, T)>
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Hash + Eq
+    {
+        let hash = self.hash_builder.hash_one(k);
+        let entry = self.ids.find_entry(hash, Self::matches(&self.map, k)).ok()?;
+        let (id, _) = entry.remove();
+        let slot = self.map.remove_id(id).expect("reverse index refers to an empty slot");
+        Some((id, slot))
+    }
+
+    pub fn remove_id(&mut self, id: I) -> Option<T> {
+        let slot = self.map.remove_id(id)?;
+        let hash = self.hash_builder.hash_one(slot.key());
+        if let Ok(entry) = self.ids.find_entry(hash, |&other| other == id) {
+            entry.remove();
+        }
+        Some(slot)
+    }
+
+    /// The number of keys in the bimap.
+    pub fn len(&self) -> usize {
+        self.map.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.map.is_empty()
+    }
+
+    /// The number of keys the bimap can hold without reallocating either
+    /// its slots or its reverse index.
+    pub fn capacity(&self) -> usize {
+        self.map.capacity().min(self.ids.capacity())
+    }
+
+    /// Reserves room for at least `additional` more keys.
+    pub fn reserve(&mut self, additional: usize) {
+        self.map.reserve(additional);
+        let Self { map, ids, hash_builder, .. } = self;
+        ids.reserve(additional, |&id| hash_builder.hash_one(map.occupied(id).key()));
+    }
+
+    /// Releases unused memory and, like `CompactIdMap::shrink_to_fit`,
+    /// forgets the highest IDs when they are all free.
+    pub fn shrink_to_fit(&mut self) {
+        self.map.shrink_to_fit();
+        let Self { map, ids, hash_builder, .. } = self;
+        ids.shrink_to_fit(|&id| hash_builder.hash_one(map.occupied(id).key()));
+    }
+
+    /// Iterates over `(id, &slot)` pairs in ascending ID order.
+    pub fn iter(&self) -> Iter<'_, I, T> {
+        self.map.iter()
+    }
+
+    /// Iterates over the live IDs in ascending order.
+    pub fn ids(&self) -> impl DoubleEndedIterator<Item = I> + '_ {
+        self.map.ids()
+    }
+
+    /// Iterates over the keys in ascending ID order.
+    pub fn keys(&self) -> impl DoubleEndedIterator<Item = &K> + '_ {
+        self.map.values().map(T::key)
+    }
+
+    /// Removes every entry, yielding `(id, slot)` pairs in ascending ID
+    /// order. IDs are handed out from the first one again afterwards, unless
+    /// the policy is `RecyclePolicy::Never`.
+    pub fn drain(&mut self) -> Drain<'_, I, T> {
+        let drain = self.map.drain();
+        self.ids.clear();
+        drain
+    }
+
+    /// Renumbers the live IDs into `0..len` keeping their relative order, so
+    /// that no recycled holes remain. Returns the table needed to update IDs
+    /// held elsewhere.
+    pub fn compact(&mut self) -> IdRemap<I> {
+        let remap = self.map.compact();
+        for id in self.ids.iter_mut() {
+            *id = remap.get(*id).expect("reverse index refers to a live ID");
+        }
+        remap
+    }
+
+    /// Opens a checkpoint; see `CompactIdMap::checkpoint`. Rolling back also
+    /// undoes `replace_key`.
+    pub fn checkpoint(&mut self) -> Checkpoint
+    where
+        T: Clone
+    {
+        self.map.checkpoint()
+    }
+
+    /// Closes `checkpoint`, keeping the changes made since.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `checkpoint` was not opened on this bimap or is closed.
+    pub fn commit(&mut self, checkpoint: Checkpoint) {
+        self.map.commit(checkpoint);
     }
 
-    fn get_fresh_id(&mut self) -> ID {
-        if self.recycle_bin.is_empty() {
-            let id = self.next_new_id;
-            self.next_new_id += 1;
-            id
-        } else {
-            self.recycle_bin.pop().unwrap()
+    /// Undoes every change made since `checkpoint` and closes it.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `checkpoint` was not opened on this bimap or is closed.
+    pub fn rollback(&mut self, checkpoint: Checkpoint) {
+        let Self { map, ids, hash_builder, .. } = self;
+        map.rollback_with(checkpoint, |map, id, restored| {
+            let Some(slot) = map.get(id) else { return };
+            let hash = hash_builder.hash_one(slot.key());
+            if restored {
+                ids.insert_unique(hash, id, |&other| hash_builder.hash_one(map.occupied(other).key()));
+            } else if let Ok(entry) = ids.find_entry(hash, |&other| other == id) {
+                entry.remove();
+            }
+        });
+    }
+}
+
+impl<K, I, S> CompactIdBiMap<K, I, S> where
+K: Hash + Eq,
+I: CompactId + Eq,
+S: BuildHasher
+{
+    /// Returns the ID of `k`, inserting it first if needed.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `k` is new and no ID is left; see `try_get_or_insert`.
+    pub fn get_or_insert(&mut self, k: K) -> I {
+        self.entry(k).or_insert()
+    }
+
+    /// Returns the ID of `k`, inserting it first if needed, or an error if
+    /// `k` is new and no ID is left (see `try_insert`).
+    pub fn try_get_or_insert(&mut self, k: K) -> Result<I, Error> {
+        match self.entry(k) {
+            Entry::Occupied(entry) => Ok(entry.id()),
+            Entry::Vacant(entry) => entry.try_insert(),
         }
     }
 
-    pub fn remove<Q: ?Sized>(&mut self, k: &Q) -> Option<ID>
+    /// Like `get_or_insert`, but only builds an owned key (e.g. allocates a
+    /// `String` from a `&str`) when `key` is not interned yet. The key is
+    /// hashed once either way.
+    pub fn get_or_insert_borrowed<Q>(&mut self, key: &Q) -> I
     where
         K: Borrow<Q>,
-        Q: Hash + Eq
+        Q: ?Sized + Hash + Eq + ToOwned<Owned = K>
     {
-        self.ids.remove(k).map(|id| {
-            self.recycle_bin.push(id);
-            self.keys.remove(&id);
-            id
-        })
+        match self.lookup(key) {
+            (hash_table::Entry::Occupied(index), _) => *index.get(),
+            (hash_table::Entry::Vacant(index), map) => {
+                let id = map.insert(key.to_owned());
+                index.insert(id);
+                id
+            }
+        }
+    }
+
+    /// Inserts a key that is not in the map yet and returns its new ID.
+    ///
+    /// # Panics
+    ///
+    /// Panics if no ID is left; see `try_insert`.
+    pub fn insert(&mut self, k: K) -> I {
+        self.try_insert(k).unwrap_or_else(|err| panic!("{err}"))
+    }
+
+    /// Inserts a key that is not in the map yet and returns its new ID. If no
+    /// ID is left, `k` is dropped and `Error::IdSpaceExhausted` or
+    /// `Error::IdLimitReached` is returned.
+    pub fn try_insert(&mut self, k: K) -> Result<I, Error> {
+        let hash = self.hash_builder.hash_one(&k);
+        debug_assert!(self.get(&k).is_none());
+        let id = self.map.try_insert(k)?;
+        self.index_new(hash, id);
+        Ok(id)
+    }
+}
+
+impl<K, I, S, T> IntoIterator for CompactIdBiMap<K, I, S, T> where
+K: Hash + Eq,
+I: CompactId
+{
+    type Item = (I, T);
+    type IntoIter = IntoIter<I, T>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.map.into_iter()
+    }
+}
+
+impl<'a, K, I, S, T> IntoIterator for &'a CompactIdBiMap<K, I, S, T> where
+K: Hash + Eq,
+I: CompactId
+{
+    type Item = (I, &'a T);
+    type IntoIter = Iter<'a, I, T>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.map.iter()
+    }
+}
+
+/// Upgrades a map into a bimap by indexing the keys of its slots, keeping
+/// every ID and the allocator state. Fails with `Error::DuplicateKey` if a
+/// key is stored under more than one ID.
+impl<K, I, S, T> TryFrom<CompactIdMap<I, T>> for CompactIdBiMap<K, I, S, T> where
+K: Hash + Eq,
+I: CompactId + Eq,
+S: BuildHasher + Default,
+T: Slot<K>
+{
+    type Error = Error;
+
+    fn try_from(map: CompactIdMap<I, T>) -> Result<Self, Error> {
+        Self::from_map_with_hasher(map, S::default())
+    }
+}
+
+/// The bimap is serialized as its underlying `CompactIdMap`, so every key is
+/// written once; the reverse index is rebuilt on load, with `S::default()` as
+/// the hasher.
+impl<K, I, S, T> Serialize for CompactIdBiMap<K, I, S, T> where
+K: Hash + Eq,
+I: CompactId + Serialize,
+T: Serialize
+{
+    fn serialize<Ser: Serializer>(&self, serializer: Ser) -> Result<Ser::Ok, Ser::Error> {
+        self.map.serialize(serializer)
+    }
+}
+
+impl<'de, K, I, S, T> Deserialize<'de> for CompactIdBiMap<K, I, S, T> where
+K: Hash + Eq,
+I: CompactId + Eq + Deserialize<'de>,
+S: BuildHasher + Default,
+T: Slot<K> + Deserialize<'de>
+{
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        CompactIdBiMapSeed::new(S::default()).deserialize(deserializer)
     }
+}
+
+/// Loads a `CompactIdBiMap` hashing its keys with a given hasher, for
+/// hashers without a meaningful `Default` such as keyed ones.
+///
+/// ```
+/// use compact_id_map::{CompactIdBiMap, CompactIdBiMapSeed};
+/// use hashbrown::DefaultHashBuilder;
+/// use serde::de::DeserializeSeed;
+///
+/// let mut deserializer = serde_json::Deserializer::from_str(r#"{"keys":["a","b"]}"#);
+/// let seed = CompactIdBiMapSeed::new(DefaultHashBuilder::default());
+/// let ids: CompactIdBiMap<String, u32> = seed.deserialize(&mut deserializer).unwrap();
+/// assert_eq!(Some(1), ids.get("b"));
+/// ```
+pub struct CompactIdBiMapSeed<K, I, S, T = K> {
+    hash_builder: S,
+    _map: PhantomData<fn() -> CompactIdMap<I, T>>,
+    _keys: PhantomData<fn() -> K>,
+}
 
-    pub fn remove_id(&mut self, id: ID) -> Option<K> {
-        self.keys.remove(&id).map(|k| {
-            self.recycle_bin.push(id);
-            self.ids.remove(&k);
-            k
-        })
+impl<K, I, S, T> CompactIdBiMapSeed<K, I, S, T> {
+    pub fn new(hash_builder: S) -> Self {
+        Self { hash_builder, _map: PhantomData, _keys: PhantomData }
     }
 }
 
-#[derive(Clone, Debug, Serialize, Deserialize)]
-pub struct CompactIdMap<I: Eq + Hash, K>
+impl<'de, K, I, S, T> DeserializeSeed<'de> for CompactIdBiMapSeed<K, I, S, T> where
+K: Hash + Eq,
+I: CompactId + Eq + Deserialize<'de>,
+S: BuildHasher,
+T: Slot<K> + Deserialize<'de>
 {
-    keys: HashMap<I, K>,
-    recycle_bin: Vec<I>,
+    type Value = CompactIdBiMap<K, I, S, T>;
+
+    fn deserialize<D: Deserializer<'de>>(self, deserializer: D) -> Result<Self::Value, D::Error> {
+        let map = CompactIdMap::deserialize(deserializer)?;
+        CompactIdBiMap::from_map_with_hasher(map, self.hash_builder).map_err(D::Error::custom)
+    }
+}
+
+/// Values stored under compact IDs, indexed directly by ID.
+///
+/// Unlike `CompactIdBiMap`, this map never hashes anything, so it has no
+/// hasher parameter.
+#[derive(Debug, Deserialize)]
+#[serde(
+    try_from = "CompactIdMapRepr<I, K>",
+    bound(deserialize = "I: CompactId + Deserialize<'de>, K: Deserialize<'de>")
+)]
+pub struct CompactIdMap<I, K>
+{
+    /// One slot per ID below `next_new_id`; slots past the end are vacant.
+    keys: Vec<Option<K>>,
+    recycle_bin: RecycleBin<I>,
     next_new_id: I,
+    policy: RecyclePolicy,
+    max_id: Option<I>,
+    /// The number of filled slots.
+    len: usize,
+    /// The generation of each slot for generational IDs, empty otherwise.
+    /// Slots past the end are in generation 0. Unlike `keys`, this is never
+    /// shortened, as freed slots must not go back to an earlier generation.
+    generations: Vec<u32>,
+    /// Changes since the outermost open checkpoint; not serialized.
+    undo: UndoLog<I, K>,
+}
+
+/// The fields of a `CompactIdMap` as read from the wire, before the
+/// allocator state is reconstructed and validated.
+#[derive(Deserialize)]
+pub(crate) struct CompactIdMapRepr<I, K> {
+    pub(crate) keys: Vec<Option<K>>,
+    pub(crate) recycle_bin: Option<VecDeque<I>>,
+    pub(crate) next_new_id: Option<I>,
+    #[serde(default)]
+    pub(crate) policy: RecyclePolicy,
+    pub(crate) max_id: Option<I>,
+    #[serde(default)]
+    pub(crate) generations: Vec<u32>,
+}
+
+/// The generation of a slot that is retired: its generation would wrap
+/// around, so it stays vacant and its ID is never handed out again.
+const RETIRED: u32 = u32::MAX;
+
+/// The generation of slot `index`.
+fn generation_of(generations: &[u32], index: usize) -> u32 {
+    generations.get(index).copied().unwrap_or(0)
+}
+
+/// The recycle bin implied by the holes in `keys` when none is written: every
+/// vacant slot below `end` that is not retired, in ascending order, or
+/// nothing if the policy never recycles. IDs past the last slot are never
+/// implied, so that a huge `next_new_id` cannot make a small input expand
+/// into a huge recycle bin.
+fn implied_recycle_bin<'a, K>(
+    keys: &'a [Option<K>],
+    generations: &'a [u32],
+    end: usize,
+    policy: RecyclePolicy,
+) -> impl Iterator<Item = usize> + 'a {
+    let end = if policy == RecyclePolicy::Never { 0 } else { end.min(keys.len()) };
+    (0..end).filter(|&index| keys[index].is_none() && generation_of(generations, index) != RETIRED)
+}
+
+/// Every key is written once, in ID order, with `null` for free slots. The
+/// recycle bin and `next_new_id` are left out whenever they can be
+/// reconstructed from those holes, i.e. when `next_new_id` is just past the
+/// last slot and the recycle bin holds the free IDs in ascending order.
+/// Otherwise they are written out, so that a map always comes back handing
+/// out exactly the same IDs. `max_id` is left out when there is none, and
+/// `generations` unless the IDs are generational.
+impl<I, K> Serialize for CompactIdMap<I, K> where
+I: CompactId + Serialize,
+K: Serialize
+{
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let end = self.next_new_id.to_index();
+        let next_new_id = (end != Some(self.keys.len())).then_some(self.next_new_id);
+        let implied = end.is_some_and(|end| {
+            self.recycle_bin.iter().map(|id| id.to_index())
+                .eq(implied_recycle_bin(&self.keys, &self.generations, end, self.policy).map(Some))
+        });
+        let recycle_bin = (!implied).then_some(&self.recycle_bin);
+
+        let generations = (!self.generations.is_empty()).then_some(&self.generations);
+        let written = [recycle_bin.is_some(), next_new_id.is_some(), self.max_id.is_some(), generations.is_some()];
+        let len = 2 + written.iter().filter(|&&written| written).count();
+        let mut state = serializer.serialize_struct("CompactIdMap", len)?;
+        state.serialize_field("keys", &self.keys)?;
+        serialize_if_some(&mut state, "recycle_bin", recycle_bin)?;
+        serialize_if_some(&mut state, "next_new_id", next_new_id)?;
+        state.serialize_field("policy", &self.policy)?;
+        serialize_if_some(&mut state, "max_id", self.max_id)?;
+        serialize_if_some(&mut state, "generations", generations)?;
+        state.end()
+    }
+}
+
+/// Writes `value` as field `key`, or skips the field if it is `None`.
+fn serialize_if_some<S, T>(state: &mut S, key: &'static str, value: Option<T>) -> Result<(), S::Error>
+where
+    S: SerializeStruct,
+    T: Serialize
+{
+    match value {
+        Some(value) => state.serialize_field(key, &value),
+        None => state.skip_field(key),
+    }
+}
+
+impl<I, K> TryFrom<CompactIdMapRepr<I, K>> for CompactIdMap<I, K> where
+I: CompactId
+{
+    type Error = Error;
+
+    fn try_from(repr: CompactIdMapRepr<I, K>) -> Result<Self, Error> {
+        let CompactIdMapRepr { keys, recycle_bin, next_new_id, policy, max_id, generations } = repr;
+        let next_new_id = match next_new_id {
+            Some(id) => id,
+            None => I::from_index(keys.len()).ok_or(Error::IdOutOfRange { index: keys.len() })?,
+        };
+        let recycle_bin = match recycle_bin {
+            Some(recycle_bin) => recycle_bin,
+            None => {
+                let end = next_new_id.to_index().ok_or(Error::InvalidId)?;
+                implied_recycle_bin(&keys, &generations, end, policy)
+                    .map(|index| {
+                        let id = I::from_index(index).ok_or(Error::IdOutOfRange { index })?;
+                        Ok(id.with_generation(generation_of(&generations, index)))
+                    })
+                    .collect::<Result<_, _>>()?
+            }
+        };
+        let recycle_bin = RecycleBin::from_ids(policy, recycle_bin)?;
+        let len = keys.iter().flatten().count();
+        let map = Self {
+            keys,
+            recycle_bin,
+            next_new_id,
+            policy,
+            max_id,
+            len,
+            generations,
+            undo: UndoLog::default(),
+        };
+        map.validate()?;
+        Ok(map)
+    }
+}
+
+/// The clone starts without open checkpoints: those of the original cannot
+/// be closed on the clone, as `Checkpoint` is not `Clone`.
+impl<I, K> Clone for CompactIdMap<I, K> where
+I: Clone,
+K: Clone
+{
+    fn clone(&self) -> Self {
+        Self {
+            keys: self.keys.clone(),
+            recycle_bin: self.recycle_bin.clone(),
+            next_new_id: self.next_new_id.clone(),
+            policy: self.policy,
+            max_id: self.max_id.clone(),
+            len: self.len,
+            generations: self.generations.clone(),
+            undo: UndoLog::default(),
+        }
+    }
+}
+
+impl<I, K> Default for CompactIdMap<I, K> where
+I: CompactId
+{
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl<I, K> CompactIdMap<I, K> where
-I: Hash + Incrementable + Default + Eq + Copy
+I: CompactId
 {
     pub fn new() -> Self {
+        Self::with_options(IdMapOptions::new())
+    }
+
+    /// An empty map with all of the given settings.
+    pub fn with_options(options: IdMapOptions<I>) -> Self {
         Self {
-            keys: HashMap::new(),
-            recycle_bin: Vec::new(),
-            next_new_id: Default::default(),
+            keys: Vec::with_capacity(options.capacity),
+            recycle_bin: RecycleBin::new(options.policy),
+            next_new_id: Self::first_id(),
+            policy: options.policy,
+            max_id: options.max_id,
+            len: 0,
+            generations: Vec::new(),
+            undo: UndoLog::default(),
         }
     }
 
+    pub fn with_policy(policy: RecyclePolicy) -> Self {
+        Self::with_options(IdMapOptions::new().policy(policy))
+    }
+
+    /// An empty map with room for `capacity` values before reallocating.
+    pub fn with_capacity(capacity: usize) -> Self {
+        Self::with_options(IdMapOptions::new().capacity(capacity))
+    }
+
+    /// A map that never hands out an ID above `max_id`, while still reusing
+    /// freed IDs below it.
+    pub fn with_max_id(max_id: I) -> Self {
+        Self::with_options(IdMapOptions::new().max_id(max_id))
+    }
+
+    pub fn policy(&self) -> RecyclePolicy {
+        self.policy
+    }
+
+    pub fn max_id(&self) -> Option<I> {
+        self.max_id
+    }
+
+    /// Checks that the allocator state agrees with the slots: every slot is
+    /// below `next_new_id` (and at most the maximum ID), every recycled ID is
+    /// unique, below `next_new_id` and vacant, and (unless the policy never
+    /// recycles) every vacant ID below `next_new_id` is in the recycle bin.
+    /// The bin is kept sorted under `LowestFirst`, and loading rejects one
+    /// that is not. Generations are only allowed for generational IDs, and
+    /// retired slots must be neither live nor recycled. Maps built through
+    /// this API always pass; this is meant for data from elsewhere, and runs
+    /// on every deserialization.
+    pub fn validate(&self) -> Result<(), Error> {
+        let end = self.next_new_id.to_index().ok_or(Error::InvalidId)?;
+        if self.keys.len() > end {
+            return Err(Error::IdOutOfRange { index: end });
+        }
+        if !I::GENERATIONAL && !self.generations.is_empty() {
+            return Err(Error::UnexpectedGenerations);
+        }
+        let live_retired = |index: usize| self.keys[index].is_some() && self.is_retired(index);
+        if let Some(index) = (0..self.keys.len()).find(|&index| live_retired(index)) {
+            return Err(Error::RetiredIdInUse { index });
+        }
+        if let Some(max_id) = self.max_id {
+            let max_index = max_id.to_index().ok_or(Error::InvalidId)?;
+            if end.checked_sub(1).is_some_and(|last| last > max_index) {
+                return Err(Error::IdAboveLimit { index: end - 1 });
+            }
+        }
+        // Only allocate in proportion to the recycle bin and the slots, as
+        // `next_new_id` may come from untrusted input.
+        let mut recycled = HashSet::with_capacity(self.recycle_bin.len());
+        for &id in self.recycle_bin.iter() {
+            let index = id.to_index().ok_or(Error::InvalidId)?;
+            if self.policy == RecyclePolicy::Never {
+                return Err(Error::RecycledIdWithoutRecycling { index });
+            }
+            if index >= end {
+                return Err(Error::IdOutOfRange { index });
+            }
+            if self.keys.get(index).is_some_and(Option::is_some) {
+                return Err(Error::RecycledIdInUse { index });
+            }
+            if self.is_retired(index) {
+                return Err(Error::RetiredIdInUse { index });
+            }
+            if !recycled.insert(index) {
+                return Err(Error::DuplicateRecycledId { index });
+            }
+        }
+        // The recycled IDs are distinct, vacant, not retired and below `end`,
+        // so they are all such IDs exactly when there are as many. Otherwise
+        // one of the first `keys.len() + retired + recycled.len() + 1` IDs is
+        // missing.
+        let retired = self.generations.iter().take(end).filter(|&&generation| generation == RETIRED);
+        let retired = retired.count();
+        if self.policy != RecyclePolicy::Never && recycled.len() != end - self.len - retired {
+            let free = |index: usize| {
+                self.keys.get(index).is_none_or(Option::is_none) && !self.is_retired(index)
+            };
+            let index = (0..end).find(|&index| free(index) && !recycled.contains(&index))
+                .expect("fewer IDs recycled than free");
+            return Err(Error::LeakedId { index });
+        }
+        Ok(())
+    }
+
+    fn first_id() -> I {
+        I::from_index(0).expect("ID type cannot represent the first ID")
+    }
+
+    /// The slot index of `id`, if `id` is from the current generation of
+    /// its slot.
+    fn index_of(&self, id: I) -> Option<usize> {
+        let index = id.to_index()?;
+        (id.generation() == generation_of(&self.generations, index)).then_some(index)
+    }
+
+    fn is_retired(&self, index: usize) -> bool {
+        generation_of(&self.generations, index) == RETIRED
+    }
+
+    /// The ID of slot `index` in its current generation.
+    fn id_at(&self, index: usize) -> I {
+        let id = I::from_index(index).expect("slots below next_new_id have an ID");
+        id.with_generation(generation_of(&self.generations, index))
+    }
+
+    /// Moves slot `index` to its next generation, so that the IDs handed out
+    /// for it no longer match. Returns `false` if the slot is retired instead
+    /// because its generation would wrap around.
+    fn next_generation(&mut self, index: usize) -> bool {
+        if !I::GENERATIONAL {
+            return true;
+        }
+        if index >= self.generations.len() {
+            self.generations.resize(index + 1, 0);
+        }
+        debug_assert!(self.generations[index] != RETIRED);
+        self.generations[index] += 1;
+        self.generations[index] != RETIRED
+    }
+
     pub fn get(&self, id: I) -> Option<&K> {
-        self.keys.get(&id)
+        self.keys.get(self.index_of(id)?)?.as_ref()
+    }
+
+    pub fn contains_id(&self, id: I) -> bool {
+        self.get(id).is_some()
+    }
+
+    /// Returns the key of an ID known to be live, such as one held by the
+    /// reverse index of a bimap.
+    pub(crate) fn occupied(&self, id: I) -> &K {
+        self.get(id).expect("reverse index refers to an empty slot")
     }
 
     pub fn get_mut(&mut self, id: I) -> Option<&mut K> {
-        self.keys.get_mut(&id)
+        let index = self.index_of(id)?;
+        self.keys.get_mut(index)?.as_mut()
+    }
+
+    /// Inserts `k` under a fresh ID.
+    ///
+    /// # Panics
+    ///
+    /// Panics if no ID is left (e.g. on the 256th live entry of a
+    /// `CompactIdMap<u8, _>`); see `try_insert`.
+    pub fn insert(&mut self, k: K) -> I {
+        self.try_insert(k).unwrap_or_else(|err| panic!("{err}"))
+    }
+
+    /// Inserts `k` under a fresh ID. If no ID is left, `k` is dropped and
+    /// `Error::IdSpaceExhausted` (the ID type has no next ID) or
+    /// `Error::IdLimitReached` (the maximum ID set with `with_max_id` is in
+    /// use) is returned. Freed IDs are still reused in either case.
+    pub fn try_insert(&mut self, k: K) -> Result<I, Error> {
+        let (slots, recyclable) = (self.keys.len(), self.recycle_bin.len());
+        let id = self.fresh_id()?;
+        fill_slot(&mut self.keys, id.to_index().expect("fresh IDs have a slot"), k);
+        self.len += 1;
+        let recycled = self.recycle_bin.len() < recyclable;
+        self.undo.record(|_| Change::Insert { id, recycled, slots });
+        Ok(id)
+    }
+
+    /// Changes the value of a live ID through `f` as a change `rollback`
+    /// undoes, unlike writes through `get_mut`.
+    pub(crate) fn update<R, F: FnOnce(&mut K) -> R>(&mut self, id: I, f: F) -> R {
+        let index = id.to_index().expect("updated IDs are live");
+        let k = self.keys[index].as_mut().expect("updated IDs are live");
+        self.undo.record(|copy_key| Change::Replace { id, k: copy_key(k) });
+        f(k)
     }
 
-    pub fn insert(&mut self, k: K) -> I
+    /// Indexes the values so that their IDs can be looked up with
+    /// `CompactIdBiMap::get`; see the `TryFrom` implementation.
+    pub fn into_bimap(self) -> Result<CompactIdBiMap<K, I>, Error>
     where
-        K: Clone,
+        K: Hash + Eq,
+        I: Eq
     {
-        let id = self.fresh_id();
-        self.keys.insert(id, k);
-        id
+        CompactIdBiMap::try_from(self)
+    }
+
+    /// Reserves the ID the next `insert` will return, so that the value can
+    /// embed its own ID.
+    pub fn vacant_entry(&mut self) -> VacantIdEntry<'_, I, K> {
+        VacantIdEntry { map: self }
     }
 
-    fn fresh_id(&mut self) -> I {
-        if self.recycle_bin.is_empty() {
-            let id = self.next_new_id;
-            self.next_new_id = self.next_new_id.increment().unwrap();
-            id
-        } else {
-            self.recycle_bin.pop().unwrap()
+    /// The ID the next `insert` will return, or the error it will fail with.
+    pub(crate) fn peek_fresh_id(&self) -> Result<I, Error> {
+        match self.policy.peek(&self.recycle_bin) {
+            Some(id) => Ok(self.id_at(id.to_index().expect("recycled IDs have a slot"))),
+            None => self.next_mintable_id().map(|(id, _)| id),
         }
     }
 
+    /// The next new ID and the `next_new_id` that minting it leaves.
+    fn next_mintable_id(&self) -> Result<(I, I), Error> {
+        let index = self.next_mintable_index();
+        let id = I::from_index(index).ok_or(Error::IdSpaceExhausted)?;
+        if self.max_id.is_some_and(|max_id| Some(index) > max_id.to_index()) {
+            return Err(Error::IdLimitReached);
+        }
+        let next_new_id = index.checked_add(1)
+            .and_then(I::from_index)
+            .ok_or(Error::IdSpaceExhausted)?;
+        Ok((id.with_generation(generation_of(&self.generations, index)), next_new_id))
+    }
+
+    /// The slot of the next new ID: `next_new_id`, or the first slot after
+    /// it that is not retired.
+    fn next_mintable_index(&self) -> usize {
+        let mut index = self.next_new_id.to_index().expect("next_new_id has a slot");
+        while self.is_retired(index) {
+            index += 1;
+        }
+        index
+    }
+
+    fn fresh_id(&mut self) -> Result<I, Error> {
+        if let Some(id) = self.policy.take(&mut self.recycle_bin) {
+            return Ok(self.id_at(id.to_index().expect("recycled IDs have a slot")));
+        }
+        let (id, next_new_id) = self.next_mintable_id()?;
+        self.next_new_id = next_new_id;
+        Ok(id)
+    }
+
+    /// Removes the value of `id` and frees the ID. For generational IDs the
+    /// slot moves to its next generation, so `id` stays invalid even once
+    /// the slot is reused; a slot whose generation would wrap around is
+    /// retired rather than recycled.
     pub fn remove_id(&mut self, id: I) -> Option<K> {
-        self.keys.remove(&id).map(|k| {
-            self.recycle_bin.push(id);
-            k
-        })
+        let index = self.index_of(id)?;
+        let k = self.keys.get_mut(index)?.take()?;
+        self.len -= 1;
+        let generations = self.generations.len();
+        if self.next_generation(index) {
+            let freed = self.id_at(index);
+            self.policy.recycle(&mut self.recycle_bin, freed);
+        }
+        self.undo.record(|copy_key| Change::Remove { id, k: copy_key(&k), generations });
+        Some(k)
+    }
+
+    /// Opens a checkpoint. Rolling back to it undoes every insert and
+    /// removal made since, restoring the recycle bin and `next_new_id`
+    /// exactly, so that repeating the same inserts yields the same IDs.
+    /// Values changed through `get_mut` are not restored.
+    ///
+    /// Checkpoints nest: an inner one can be committed or rolled back on its
+    /// own, and closing an outer one also closes those opened after it. Keys
+    /// handed back by removals are cloned into the log, hence `K: Clone`.
+    ///
+    /// A checkpoint must be passed to `commit` or `rollback`; dropping it
+    /// leaves it open, and with it the log and the restrictions it brings.
+    pub fn checkpoint(&mut self) -> Checkpoint
+    where
+        K: Clone
+    {
+        self.undo.open(K::clone)
+    }
+
+    /// Closes `checkpoint`, keeping the changes made since. An enclosing
+    /// checkpoint can still roll them back.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `checkpoint` was not opened on this map or is closed.
+    pub fn commit(&mut self, checkpoint: Checkpoint) {
+        self.undo.commit(checkpoint);
+    }
+
+    /// Undoes every change made since `checkpoint` and closes it.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `checkpoint` was not opened on this map or is closed.
+    pub fn rollback(&mut self, checkpoint: Checkpoint) {
+        self.rollback_with(checkpoint, |_, _, _| {});
+    }
+
+    /// Rolls back to `checkpoint`, calling `reindex(self, id, false)` before
+    /// and `reindex(self, id, true)` after each slot is restored, so that a
+    /// bimap can keep its reverse index in sync.
+    pub(crate) fn rollback_with<F>(&mut self, checkpoint: Checkpoint, mut reindex: F)
+    where
+        F: FnMut(&Self, I, bool)
+    {
+        for change in self.undo.rollback(checkpoint).into_iter().rev() {
+            let (Change::Insert { id, .. } | Change::Remove { id, .. } | Change::Replace { id, .. }) = change;
+            reindex(self, id, false);
+            let index = id.to_index().expect("logged IDs have a slot");
+            match change {
+                Change::Insert { id, recycled, slots } => {
+                    self.keys[index] = None;
+                    self.keys.truncate(slots);
+                    self.len -= 1;
+                    if recycled {
+                        self.policy.untake(&mut self.recycle_bin, id);
+                    } else {
+                        self.next_new_id = I::from_index(index).expect("logged IDs have a slot");
+                    }
+                }
+                Change::Remove { id, k, generations } => {
+                    if !self.is_retired(index) {
+                        self.policy.unrecycle(&mut self.recycle_bin, id);
+                    }
+                    if I::GENERATIONAL {
+                        self.generations[index] -= 1;
+                        self.generations.truncate(generations);
+                    }
+                    self.keys[index] = Some(k);
+                    self.len += 1;
+                }
+                Change::Replace { k, .. } => self.keys[index] = Some(k),
+            }
+            reindex(self, id, true);
+        }
+    }
+
+    /// Panics if a checkpoint is open, for operations that cannot be undone.
+    fn assert_no_checkpoint(&self, operation: &str) {
+        assert!(!self.undo.is_open(), "cannot {operation} a map with an open checkpoint");
+    }
+
+    /// The number of values in the map.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// The number of slots the map can hold without reallocating. Slots of
+    /// freed IDs count as used, as they are kept for reuse.
+    pub fn capacity(&self) -> usize {
+        self.keys.capacity()
+    }
+
+    /// Reserves room for at least `additional` more values.
+    pub fn reserve(&mut self, additional: usize) {
+        self.keys.reserve(additional);
+    }
+
+    /// Releases unused memory. When the highest IDs are all free, they are
+    /// also dropped from the recycle bin and `next_new_id` is lowered to just
+    /// past the last live ID, so they are handed out fresh again. With
+    /// `RecyclePolicy::Never` freed IDs are never handed out again, so only
+    /// memory is released.
+    ///
+    /// # Panics
+    ///
+    /// Panics if a checkpoint is open.
+    pub fn shrink_to_fit(&mut self) {
+        self.assert_no_checkpoint("shrink");
+        let end = self.keys.iter().rposition(Option::is_some).map_or(0, |last| last + 1);
+        self.keys.truncate(end);
+        if self.policy != RecyclePolicy::Never {
+            self.recycle_bin.retain(|id| id.to_index().is_some_and(|index| index < end));
+            self.next_new_id = I::from_index(end).expect("below the old next_new_id");
+        }
+        let generations = self.generations.iter().rposition(|&generation| generation != 0);
+        self.generations.truncate(generations.map_or(0, |last| last + 1));
+        self.keys.shrink_to_fit();
+        self.recycle_bin.shrink_to_fit();
+        self.generations.shrink_to_fit();
+    }
+
+    /// Iterates over `(id, &value)` pairs in ascending ID order.
+    pub fn iter(&self) -> Iter<'_, I, K> {
+        Iter::new(&self.keys, &self.generations)
+    }
+
+    /// Iterates over `(id, &mut value)` pairs in ascending ID order.
+    pub fn iter_mut(&mut self) -> IterMut<'_, I, K> {
+        IterMut::new(&mut self.keys, &self.generations)
+    }
+
+    /// Iterates over the live IDs in ascending order.
+    pub fn ids(&self) -> impl DoubleEndedIterator<Item = I> + '_ {
+        self.iter().map(|(id, _)| id)
+    }
+
+    /// Iterates over the values in ascending ID order.
+    pub fn values(&self) -> impl DoubleEndedIterator<Item = &K> + '_ {
+        self.iter().map(|(_, k)| k)
+    }
+
+    /// Iterates mutably over the values in ascending ID order.
+    pub fn values_mut(&mut self) -> impl DoubleEndedIterator<Item = &mut K> + '_ {
+        self.iter_mut().map(|(_, k)| k)
+    }
+
+    /// Removes every entry, yielding `(id, value)` pairs in ascending ID
+    /// order. IDs are handed out from the first one again afterwards, unless
+    /// the policy is `RecyclePolicy::Never`; generational IDs come back in a
+    /// later generation, as after `remove_id`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if a checkpoint is open.
+    pub fn drain(&mut self) -> Drain<'_, I, K> {
+        self.assert_no_checkpoint("drain");
+        let generations = self.generations.clone();
+        if I::GENERATIONAL {
+            for index in 0..self.keys.len() {
+                if self.keys[index].is_some() {
+                    self.next_generation(index);
+                }
+            }
+        }
+        self.recycle_bin.clear();
+        if self.policy != RecyclePolicy::Never {
+            self.next_new_id = Self::first_id();
+        }
+        self.len = 0;
+        Drain::new(&mut self.keys, generations)
+    }
+
+    /// Renumbers the live IDs into `0..len` keeping their relative order, so
+    /// that no recycled holes remain. Returns the table needed to update IDs
+    /// held elsewhere.
+    ///
+    /// # Panics
+    ///
+    /// Panics if a checkpoint is open.
+    pub fn compact(&mut self) -> IdRemap<I> {
+        self.assert_no_checkpoint("compact");
+        let slots = std::mem::take(&mut self.keys);
+        let mut new_ids = Vec::with_capacity(slots.len());
+        for (index, slot) in slots.into_iter().enumerate() {
+            let Some(k) = slot else {
+                new_ids.push(None);
+                continue;
+            };
+            let old = self.id_at(index);
+            // Move down to the first slot that is not retired. A slot that
+            // changes hands moves to its next generation, unless that retires
+            // it, in which case the next one is tried.
+            loop {
+                let target = self.keys.len();
+                if target == index || (!self.is_retired(target) && self.next_generation(target)) {
+                    break;
+                }
+                self.keys.push(None);
+            }
+            self.keys.push(Some(k));
+            new_ids.push(Some((old.generation(), self.id_at(self.keys.len() - 1))));
+        }
+        // Entries moved out of the slots past the new end, which have not
+        // changed generation yet.
+        let end = self.keys.len();
+        for (index, _) in new_ids.iter().enumerate().skip(end).filter(|(_, new)| new.is_some()) {
+            self.next_generation(index);
+        }
+        self.recycle_bin.clear();
+        self.next_new_id = I::from_index(self.keys.len()).expect("compacted IDs are below the old ones");
+        IdRemap::new(new_ids)
+    }
+}
+
+impl<I, K> IntoIterator for CompactIdMap<I, K> where
+I: CompactId
+{
+    type Item = (I, K);
+    type IntoIter = IntoIter<I, K>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        IntoIter::new(self.keys, self.generations)
+    }
+}
+
+impl<'a, I, K> IntoIterator for &'a CompactIdMap<I, K> where
+I: CompactId
+{
+    type Item = (I, &'a K);
+    type IntoIter = Iter<'a, I, K>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.iter()
+    }
+}
+
+impl<'a, I, K> IntoIterator for &'a mut CompactIdMap<I, K> where
+I: CompactId
+{
+    type Item = (I, &'a mut K);
+    type IntoIter = IterMut<'a, I, K>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.iter_mut()
     }
 }
 
@@ -164,4 +1312,574 @@ mod test_compact_id_alloc {
         assert_eq!(Some(2), ids.get("you"));
         assert_eq!(Some(&String::from("you")), ids.get_key(2));
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_map_slots() {
+        let mut map = CompactIdMap::<u8, &str>::new();
+        assert_eq!(0, map.insert("a"));
+        assert_eq!(1, map.insert("b"));
+        assert_eq!(2, map.insert("c"));
+        assert_eq!(Some("b"), map.remove_id(1));
+        assert_eq!(None, map.get(1));
+        assert_eq!(None, map.remove_id(1));
+        assert_eq!(None, map.get(200));
+        *map.get_mut(2).unwrap() = "z";
+        assert_eq!(Some(&"z"), map.get(2));
+        assert_eq!(1, map.insert("d"));
+        assert_eq!(Some(&"d"), map.get(1));
+    }
+
+    #[test]
+    fn test_bimap_slots() {
+        let mut ids = CompactIdBiMap::<&str>::new();
+        ids.insert("a");
+        ids.insert("b");
+        assert_eq!(None, ids.get_key(2));
+        assert_eq!(None, ids.get_key(usize::MAX));
+        ids.remove("b");
+        assert_eq!(None, ids.get_key(1));
+    }
+
+    #[derive(Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+    struct Payload(Vec<u8>);
+
+    #[test]
+    fn test_bimap_keys_stored_once() {
+        let mut ids = CompactIdBiMap::new();
+        assert_eq!(0, ids.get_or_insert(Payload(vec![1, 2])));
+        assert_eq!(1, ids.get_or_insert(Payload(vec![3])));
+        assert_eq!(0, ids.get_or_insert(Payload(vec![1, 2])));
+        assert_eq!(Some(Payload(vec![3])), ids.remove_id(1));
+        assert_eq!(None, ids.get(&Payload(vec![3])));
+
+        let json = serde_json::to_string(&ids).unwrap();
+        let loaded: CompactIdBiMap<Payload> = serde_json::from_str(&json).unwrap();
+        assert_eq!(Some(0), loaded.get(&Payload(vec![1, 2])));
+        assert_eq!(Some(&Payload(vec![1, 2])), loaded.get_key(0));
+
+        let duplicated = r#"{"keys":[[7],[7]],"recycle_bin":[],"next_new_id":2}"#;
+        assert!(serde_json::from_str::<CompactIdBiMap<Payload>>(duplicated).is_err());
+        assert_eq!(Ok(()), loaded.validate());
+    }
+
+    #[test]
+    fn test_bimap_id_type() {
+        let mut ids = CompactIdBiMap::<u16, u8>::new();
+        for k in 0..255 {
+            assert_eq!(k as u8, ids.insert(k * 10));
+        }
+        assert_eq!(Some(254), ids.get(&2540));
+        assert_eq!(Some(3), ids.remove(&30));
+        assert_eq!(3, ids.insert(7));
+        assert_eq!(Some(&7), ids.get_key(3));
+    }
+
+    #[test]
+    #[should_panic(expected = "ID space exhausted")]
+    fn test_bimap_id_overflow() {
+        let mut ids = CompactIdBiMap::<u16, u8>::new();
+        for k in 0..256 {
+            ids.insert(k);
+        }
+    }
+
+    #[test]
+    fn test_map_iteration() {
+        let mut map = CompactIdMap::<u32, i32>::new();
+        for v in [10, 20, 30, 40] {
+            map.insert(v);
+        }
+        map.remove_id(1);
+        assert_eq!(vec![(0, &10), (2, &30), (3, &40)], map.iter().collect::<Vec<_>>());
+        assert_eq!(vec![3, 2, 0], map.ids().rev().collect::<Vec<_>>());
+        for (id, v) in &mut map {
+            *v += id as i32;
+        }
+        assert_eq!(vec![&10, &32, &43], map.values().collect::<Vec<_>>());
+        assert_eq!(vec![(0, 10), (2, 32), (3, 43)], map.drain().collect::<Vec<_>>());
+        assert_eq!(None, map.iter().next());
+        assert_eq!(0, map.insert(5));
+        assert_eq!(vec![(0, 5)], map.into_iter().collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn test_bimap_iteration() {
+        let mut ids = CompactIdBiMap::<&str>::new();
+        for k in ["a", "b", "c"] {
+            ids.insert(k);
+        }
+        ids.remove("b");
+        assert_eq!(vec![(0, &"a"), (2, &"c")], ids.iter().collect::<Vec<_>>());
+        assert_eq!(vec![0, 2], ids.ids().collect::<Vec<_>>());
+        assert_eq!(vec![&"a", &"c"], ids.keys().collect::<Vec<_>>());
+        assert_eq!(vec![(0, "a"), (2, "c")], ids.drain().collect::<Vec<_>>());
+        assert_eq!(None, ids.get("a"));
+        assert_eq!(0, ids.insert("d"));
+        assert_eq!(vec![(0, "d")], ids.into_iter().collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn test_bimap_entry() {
+        let mut ids = CompactIdBiMap::<String>::new();
+        ids.insert(String::from("a"));
+        match ids.entry(String::from("b")) {
+            Entry::Vacant(entry) => {
+                assert_eq!(Ok(1), entry.id());
+                assert_eq!(1, entry.insert());
+            }
+            Entry::Occupied(_) => unreachable!(),
+        }
+        let mut created = Vec::new();
+        assert_eq!(0, ids.entry(String::from("a")).or_insert_with(|id| created.push(id)));
+        assert_eq!(2, ids.entry(String::from("c")).or_insert_with(|id| created.push(id)));
+        assert_eq!(vec![2], created);
+        match ids.entry(String::from("a")) {
+            Entry::Occupied(entry) => {
+                assert_eq!((0, "a"), (entry.id(), entry.key().as_str()));
+                assert_eq!("a", entry.remove());
+            }
+            Entry::Vacant(_) => unreachable!(),
+        }
+        assert_eq!(None, ids.get("a"));
+        assert_eq!(Ok(0), ids.entry(String::from("d")).id());
+        assert_eq!(0, ids.get_or_insert(String::from("d")));
+    }
+
+    #[test]
+    fn test_map_vacant_entry() {
+        #[derive(Debug, PartialEq)]
+        struct Node {
+            id: u16,
+            parent: Option<u16>,
+        }
+
+        let mut nodes = CompactIdMap::<u16, Node>::new();
+        let entry = nodes.vacant_entry();
+        let id = entry.id().unwrap();
+        entry.insert(Node { id, parent: None });
+        let entry = nodes.vacant_entry();
+        let child = Node { id: entry.id().unwrap(), parent: Some(id) };
+        assert_eq!(1, entry.insert(child).id);
+        assert_eq!(Some(&Node { id: 1, parent: Some(0) }), nodes.get(1));
+    }
+
+    #[test]
+    fn test_get_or_insert_borrowed() {
+        let mut ids = CompactIdBiMap::<String>::new();
+        assert_eq!(0, ids.get_or_insert_borrowed("tok"));
+        assert_eq!(1, ids.get_or_insert_borrowed("en"));
+        assert_eq!(0, ids.get_or_insert_borrowed("tok"));
+        assert_eq!(Some(&String::from("en")), ids.get_key(1));
+
+        let mut bytes = CompactIdBiMap::<Vec<u8>, u32>::new();
+        assert_eq!(0, bytes.get_or_insert_borrowed(&b"\x00\x01"[..]));
+        assert_eq!(Some(0), bytes.get(&b"\x00\x01"[..]));
+    }
+
+    #[test]
+    fn test_compact() {
+        let mut map = CompactIdMap::<u8, char>::new();
+        for c in "abcdef".chars() {
+            map.insert(c);
+        }
+        map.remove_id(0);
+        map.remove_id(3);
+        map.remove_id(5);
+        let remap = map.compact();
+        assert_eq!(vec![(1, 0), (2, 1), (4, 2)], remap.iter().collect::<Vec<_>>());
+        assert_eq!(None, remap.get(3));
+        assert!(!remap.is_identity());
+        assert_eq!(vec![(0, &'b'), (1, &'c'), (2, &'e')], map.iter().collect::<Vec<_>>());
+        assert_eq!(3, map.insert('g'));
+        assert!(map.compact().is_identity());
+
+        let mut ids = CompactIdBiMap::<&str>::new();
+        for k in ["x", "y", "z"] {
+            ids.insert(k);
+        }
+        ids.remove("x");
+        let remap = ids.compact();
+        assert_eq!(Some(0), remap.get(1));
+        assert_eq!(Some(1), ids.get("z"));
+        assert_eq!(Some(&"y"), ids.get_key(0));
+        assert_eq!(2, ids.insert("w"));
+    }
+
+    #[test]
+    fn test_recycle_policies() {
+        let reused = |policy| {
+            let mut map = CompactIdMap::<u32, ()>::with_policy(policy);
+            for _ in 0..5 {
+                map.insert(());
+            }
+            for id in [3, 1, 2] {
+                map.remove_id(id);
+            }
+            (0..4).map(|_| map.insert(())).collect::<Vec<_>>()
+        };
+        assert_eq!(vec![2, 1, 3, 5], reused(RecyclePolicy::Lifo));
+        assert_eq!(vec![1, 2, 3, 5], reused(RecyclePolicy::LowestFirst));
+        assert_eq!(vec![3, 1, 2, 5], reused(RecyclePolicy::Fifo));
+        assert_eq!(vec![5, 6, 7, 8], reused(RecyclePolicy::Never));
+
+        let mut ids = CompactIdBiMap::<&str>::with_policy(RecyclePolicy::Never);
+        ids.insert("a");
+        ids.remove("a");
+        assert_eq!(1, ids.insert("a"));
+        ids.drain();
+        assert_eq!(2, ids.insert("b"));
+        let json = serde_json::to_string(&ids).unwrap();
+        assert!(json.contains(r#""policy":"Never""#));
+        let mut loaded: CompactIdBiMap<&str> = serde_json::from_str(&json).unwrap();
+        assert_eq!(RecyclePolicy::Never, loaded.policy());
+        loaded.remove("b");
+        assert_eq!(3, loaded.insert("c"));
+    }
+
+    #[test]
+    fn test_validating_deserialization() {
+        fn load(json: &str) -> Result<CompactIdBiMap<String, u32>, String> {
+            serde_json::from_str(json).map_err(|err| err.to_string())
+        }
+
+        let valid = r#"{"keys":["a",null,"c"],"recycle_bin":[1],"next_new_id":3}"#;
+        assert!(load(valid).is_ok());
+        let invalid = [
+            (r#"{"keys":["a","b"],"recycle_bin":[],"next_new_id":1}"#, "ID 1 is not below"),
+            (r#"{"keys":["a",null],"recycle_bin":[1,1],"next_new_id":2}"#, "recycled more than once"),
+            (r#"{"keys":["a",null],"recycle_bin":[0,1],"next_new_id":2}"#, "still in use"),
+            (r#"{"keys":["a",null],"recycle_bin":[5],"next_new_id":2}"#, "ID 5 is not below"),
+            (r#"{"keys":["a"],"recycle_bin":[],"next_new_id":3}"#, "free ID 1 is missing"),
+            (r#"{"keys":[],"recycle_bin":[1,0],"next_new_id":2,"policy":"LowestFirst"}"#, "not sorted"),
+            (r#"{"keys":[],"recycle_bin":[0],"next_new_id":1,"policy":"Never"}"#, "never recycles"),
+            (r#"{"keys":["a","a"],"recycle_bin":[],"next_new_id":2}"#, "already used"),
+        ];
+        for (json, message) in invalid {
+            let err = load(json).unwrap_err();
+            assert!(err.contains(message), "{json}: {err}");
+        }
+
+        let never = r#"{"keys":[],"recycle_bin":[],"next_new_id":2,"policy":"Never"}"#;
+        assert_eq!(2, load(never).unwrap().insert(String::from("x")));
+    }
+
+    #[test]
+    fn test_huge_next_new_id() {
+        let never = r#"{"keys":[],"recycle_bin":[],"next_new_id":1000000000000,"policy":"Never"}"#;
+        let mut map: CompactIdMap<u64, char> = serde_json::from_str(never).unwrap();
+        assert_eq!(Ok(1_000_000_000_000), map.vacant_entry().id());
+
+        for leaked in [
+            r#"{"keys":[],"recycle_bin":[],"next_new_id":1000000000000}"#,
+            r#"{"keys":[null,"b"],"next_new_id":1000000000000}"#,
+            r#"{"keys":["a"],"recycle_bin":[1,2],"next_new_id":1000000000000}"#,
+        ] {
+            let err = serde_json::from_str::<CompactIdMap<u64, char>>(leaked).unwrap_err();
+            assert!(err.to_string().contains("is missing from the recycle bin"), "{leaked}: {err}");
+        }
+    }
+
+    #[test]
+    fn test_compact_format() {
+        let mut ids = CompactIdBiMap::<&str>::new();
+        for k in ["a", "b", "c", "d"] {
+            ids.insert(k);
+        }
+        ids.remove("b");
+        assert_eq!(
+            r#"{"keys":["a",null,"c","d"],"policy":"Lifo"}"#,
+            serde_json::to_string(&ids).unwrap()
+        );
+
+        // Freed out of order: the LIFO bin is written so reuse order survives.
+        ids.remove("d");
+        ids.remove("a");
+        let json = serde_json::to_string(&ids).unwrap();
+        assert!(json.contains(r#""recycle_bin":[1,3,0]"#), "{json}");
+        let mut loaded: CompactIdBiMap<&str> = serde_json::from_str(&json).unwrap();
+        assert_eq!([0, 3, 1], ["x", "y", "z"].map(|k| loaded.insert(k)));
+
+        let minimal = r#"{"keys":[null,"q"]}"#;
+        let mut loaded: CompactIdBiMap<&str> = serde_json::from_str(minimal).unwrap();
+        assert_eq!([0, 2], ["r", "s"].map(|k| loaded.insert(k)));
+
+        let mut never = CompactIdMap::<u8, ()>::with_policy(RecyclePolicy::Never);
+        never.insert(());
+        never.drain();
+        let json = serde_json::to_string(&never).unwrap();
+        assert_eq!(r#"{"keys":[],"next_new_id":1,"policy":"Never"}"#, json);
+    }
+
+    #[test]
+    fn test_fallible_insert() {
+        let mut map = CompactIdMap::<u8, u8>::new();
+        for v in 0..255 {
+            assert_eq!(Ok(v), map.try_insert(v));
+        }
+        assert_eq!(Err(Error::IdSpaceExhausted), map.try_insert(0));
+        map.remove_id(7);
+        assert_eq!(Ok(7), map.try_insert(0));
+        assert_eq!(Err(Error::IdSpaceExhausted), map.try_insert(0));
+
+        let mut ids = CompactIdBiMap::<u16, u8>::new();
+        for k in 0..255 {
+            ids.insert(k);
+        }
+        assert_eq!(Ok(9), ids.try_get_or_insert(9));
+        assert_eq!(Err(Error::IdSpaceExhausted), ids.try_get_or_insert(1000));
+        assert_eq!(Err(Error::IdSpaceExhausted), ids.try_insert(1000));
+        assert_eq!(None, ids.get(&1000));
+    }
+
+    #[test]
+    fn test_max_id() {
+        let mut map = CompactIdMap::<u32, char>::with_max_id(2);
+        assert_eq!(Some(2), map.max_id());
+        assert_eq!(Ok(0), map.try_insert('a'));
+        assert_eq!(Ok(1), map.try_insert('b'));
+        assert_eq!(Ok(2), map.try_insert('c'));
+        assert_eq!(Err(Error::IdLimitReached), map.try_insert('d'));
+        map.remove_id(1);
+        assert_eq!(Ok(1), map.try_insert('e'));
+        assert_eq!(Err(Error::IdLimitReached), map.try_insert('f'));
+
+        let json = serde_json::to_string(&map).unwrap();
+        let mut loaded: CompactIdMap<u32, char> = serde_json::from_str(&json).unwrap();
+        assert_eq!(Err(Error::IdLimitReached), loaded.try_insert('g'));
+        let above = r#"{"keys":["a","b"],"max_id":0}"#;
+        let err = serde_json::from_str::<CompactIdMap<u32, char>>(above).unwrap_err();
+        assert!(err.to_string().contains("ID 1 is above the maximum ID"), "{err}");
+
+        let mut ids = CompactIdBiMap::<&str, u16>::with_max_id(0);
+        assert_eq!(Ok(0), ids.try_get_or_insert("a"));
+        assert_eq!(Ok(0), ids.try_get_or_insert("a"));
+        assert_eq!(Err(Error::IdLimitReached), ids.try_get_or_insert("b"));
+    }
+
+    #[test]
+    fn test_vacant_entries_at_limit() {
+        let mut map = CompactIdMap::<u32, char>::with_max_id(0);
+        assert_eq!(Ok(0), map.vacant_entry().id());
+        assert_eq!('a', *map.vacant_entry().insert('a'));
+        assert_eq!(Err(Error::IdLimitReached), map.vacant_entry().id());
+        assert_eq!(Err(Error::IdLimitReached), map.vacant_entry().try_insert('b'));
+
+        let mut ids = CompactIdBiMap::<&str, u8>::with_max_id(0);
+        ids.insert("a");
+        match ids.entry("b") {
+            Entry::Vacant(entry) => {
+                assert_eq!(Err(Error::IdLimitReached), entry.id());
+                assert_eq!(Err(Error::IdLimitReached), entry.try_insert());
+            }
+            Entry::Occupied(_) => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn test_options() {
+        let options = IdMapOptions::new().policy(RecyclePolicy::LowestFirst).max_id(2).capacity(3);
+        let mut map = CompactIdMap::<u8, char>::with_options(options);
+        assert!(map.capacity() >= 3);
+        for c in "abc".chars() {
+            map.insert(c);
+        }
+        assert_eq!(Err(Error::IdLimitReached), map.try_insert('d'));
+        map.remove_id(2);
+        map.remove_id(0);
+        assert_eq!(Ok(0), map.try_insert('e'));
+
+        let mut ids = CompactIdBiMap::<&str, u8>::with_options(IdMapOptions::new().max_id(0).capacity(1));
+        assert!(ids.capacity() >= 1);
+        assert_eq!(Ok(0), ids.try_get_or_insert("a"));
+        assert_eq!(Err(Error::IdLimitReached), ids.try_get_or_insert("b"));
+    }
+
+    #[test]
+    fn test_custom_hasher() {
+        type Fixed = std::hash::BuildHasherDefault<std::collections::hash_map::DefaultHasher>;
+        let mut ids = CompactIdBiMap::<String, u32, Fixed>::with_capacity_and_hasher(2, Fixed::default());
+        let a = ids.get_or_insert(String::from("a"));
+        let b = ids.get_or_insert_borrowed("b");
+        assert_eq!(Some(b), ids.get("b"));
+
+        let json = serde_json::to_string(&ids).unwrap();
+        let loaded: CompactIdBiMap<String, u32, Fixed> = serde_json::from_str(&json).unwrap();
+        assert_eq!(Some(a), loaded.get("a"));
+        assert_eq!(Ok(()), loaded.validate());
+        assert_eq!(0, CompactIdBiMap::<&str, u8, Fixed>::with_hasher(Fixed::default()).ids().count());
+    }
+
+    #[test]
+    fn test_keyed_hasher() {
+        use std::{collections::hash_map::DefaultHasher, hash::Hasher};
+
+        /// A hasher with a key but no `Default`.
+        #[derive(Clone, Debug)]
+        struct Keyed(u64);
+
+        impl BuildHasher for Keyed {
+            type Hasher = DefaultHasher;
+
+            fn build_hasher(&self) -> DefaultHasher {
+                let mut hasher = DefaultHasher::new();
+                hasher.write_u64(self.0);
+                hasher
+            }
+        }
+
+        let mut ids = CompactIdBiMap::<&str, u8, Keyed>::with_max_id_and_hasher(1, Keyed(7));
+        assert_eq!(0, ids.insert("a"));
+        assert_eq!(1, ids.insert("b"));
+        assert_eq!(Err(Error::IdLimitReached), ids.try_insert("c"));
+        let ids = CompactIdBiMap::<&str, u8, Keyed>::with_policy_and_hasher(RecyclePolicy::Never, Keyed(7));
+        assert_eq!(RecyclePolicy::Never, ids.policy());
+
+        let mut map = CompactIdMap::<u8, &str>::new();
+        map.insert("a");
+        map.insert("b");
+        let ids = CompactIdBiMap::from_map_with_hasher(map, Keyed(7)).unwrap();
+        assert_eq!(Some(1), ids.get("b"));
+
+        let json = serde_json::to_string(&ids).unwrap();
+        let seed = CompactIdBiMapSeed::<&str, u8, _>::new(Keyed(7));
+        let loaded = seed.deserialize(&mut serde_json::Deserializer::from_str(&json)).unwrap();
+        assert_eq!(Some(1), loaded.get("b"));
+        assert_eq!(7, loaded.hasher().0);
+        assert_eq!(Ok(()), loaded.validate());
+    }
+
+    #[test]
+    fn test_capacity() {
+        let mut map = CompactIdMap::<u32, char>::with_capacity(8);
+        assert!(map.is_empty());
+        assert!(map.capacity() >= 8);
+        let ids: Vec<u32> = "abcdef".chars().map(|c| map.insert(c)).collect();
+        map.remove_id(ids[1]);
+        map.remove_id(ids[5]);
+        map.remove_id(ids[4]);
+        assert_eq!(3, map.len());
+        map.shrink_to_fit();
+        assert_eq!(Ok(()), map.validate());
+        assert!(map.capacity() >= map.len());
+        assert_eq!(ids[1], map.insert('x'));
+        assert_eq!(ids[4], map.insert('y'));
+        map.reserve(10);
+        assert!(map.capacity() >= 15);
+
+        let mut never = CompactIdMap::<u8, char>::with_policy(RecyclePolicy::Never);
+        let a = never.insert('a');
+        never.remove_id(a);
+        never.shrink_to_fit();
+        assert_ne!(a, never.insert('b'));
+
+        let mut names = CompactIdBiMap::<String>::with_capacity(4);
+        assert!(names.capacity() >= 4);
+        names.insert(String::from("a"));
+        let b = names.insert(String::from("b"));
+        names.remove_id(b);
+        assert_eq!(1, names.len());
+        names.shrink_to_fit();
+        assert_eq!(b, names.insert(String::from("c")));
+        names.drain();
+        assert!(names.is_empty());
+    }
+
+    #[test]
+    fn test_upgrade_to_bimap() {
+        crate::define_id!(struct TokenId(u32));
+        let mut tokens = CompactIdMap::<TokenId, String>::new();
+        let a = tokens.insert(String::from("a"));
+        let b = tokens.insert(String::from("b"));
+        tokens.get_mut(a).unwrap().push('!');
+        tokens.remove_id(b);
+
+        let mut tokens = tokens.into_bimap().unwrap();
+        assert_eq!(Some(a), tokens.get("a!"));
+        assert_eq!(b, tokens.insert(String::from("c")));
+        assert_eq!(Ok(String::from("a!")), tokens.replace_key(a, String::from("a")));
+        assert_eq!(Err(String::from("c")), tokens.replace_key(a, String::from("c")));
+        assert_eq!(Some(a), tokens.get("a"));
+        assert_eq!(None, tokens.get("a!"));
+        assert_eq!(Ok(()), tokens.validate());
+        assert_eq!(Some(&String::from("c")), tokens.into_map().get(b));
+
+        let mut twice = CompactIdMap::<u8, &str>::new();
+        twice.insert("x");
+        twice.insert("x");
+        assert_eq!(Err(Error::DuplicateKey { index: 1 }), twice.into_bimap().map(|_| ()));
+    }
+
+    #[test]
+    fn test_checkpoints() {
+        for policy in [RecyclePolicy::Lifo, RecyclePolicy::LowestFirst, RecyclePolicy::Fifo, RecyclePolicy::Never] {
+            let mut map = CompactIdMap::<u32, char>::with_policy(policy);
+            for c in "abcde".chars() {
+                map.insert(c);
+            }
+            map.remove_id(3);
+            map.remove_id(1);
+            let before = serde_json::to_string(&map).unwrap();
+
+            let outer = map.checkpoint();
+            let speculative: Vec<u32> = "xyz".chars().map(|c| map.insert(c)).collect();
+            let inner = map.checkpoint();
+            assert_eq!(Some('a'), map.remove_id(0));
+            map.insert('w');
+            map.commit(inner);
+            assert_eq!(Some('x'), map.remove_id(speculative[0]));
+            map.rollback(outer);
+            assert_eq!(before, serde_json::to_string(&map).unwrap());
+            assert_eq!(Ok(()), map.validate());
+            assert_eq!(3, map.len());
+            assert_eq!(speculative, "xyz".chars().map(|c| map.insert(c)).collect::<Vec<_>>());
+        }
+
+        let mut names = CompactIdBiMap::<String, u8>::new();
+        let a = names.insert(String::from("a"));
+        names.insert(String::from("b"));
+        let checkpoint = names.checkpoint();
+        names.remove("b");
+        let c = names.get_or_insert(String::from("c"));
+        names.replace_key(a, String::from("z")).unwrap();
+        names.rollback(checkpoint);
+        assert_eq!(Some(a), names.get("a"));
+        assert_eq!(Some(1), names.get("b"));
+        assert_eq!(None, names.get("c"));
+        assert_eq!(None, names.get("z"));
+        assert_eq!(Ok(()), names.validate());
+        names.remove("b");
+        assert_eq!(c, names.insert(String::from("c")));
+    }
+
+    #[test]
+    #[should_panic(expected = "checkpoint of another map, or already closed")]
+    fn test_checkpoint_of_another_map() {
+        let mut map = CompactIdMap::<u8, char>::new();
+        let mut other = CompactIdMap::<u8, char>::new();
+        let _checkpoint = map.checkpoint();
+        let checkpoint = other.checkpoint();
+        map.commit(checkpoint);
+    }
+
+    #[test]
+    #[should_panic(expected = "cannot compact a map with an open checkpoint")]
+    fn test_compact_in_checkpoint() {
+        let mut map = CompactIdMap::<u8, char>::new();
+        let _checkpoint = map.checkpoint();
+        map.compact();
+    }
+
+    #[test]
+    fn test_clone_in_checkpoint() {
+        let mut map = CompactIdMap::<u8, char>::new();
+        map.insert('a');
+        let checkpoint = map.checkpoint();
+        map.insert('b');
+        let mut clone = map.clone();
+        assert!(clone.compact().is_identity());
+        clone.shrink_to_fit();
+        map.rollback(checkpoint);
+        assert_eq!(1, map.len());
+        assert_eq!(2, clone.len());
+    }
+}
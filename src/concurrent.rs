@@ -0,0 +1,280 @@
+use std::{
+    borrow::Borrow,
+    hash::{BuildHasher, Hash},
+    num::NonZero,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex, OnceLock, PoisonError, RwLock,
+    },
+    thread,
+};
+
+use hashbrown::{DefaultHashBuilder, HashTable};
+
+use crate::{CompactId, Error, ID};
+
+/// A key-to-ID table holding a share of the keys.
+type Shard<K, I> = RwLock<HashTable<(Arc<K>, I)>>;
+
+/// The key of one ID, if it is live.
+type Slot<K> = RwLock<Option<Arc<K>>>;
+
+/// The size of the first chunk of slots; each further chunk doubles it.
+const BASE: usize = 32;
+
+/// Enough chunks to address every index up to `MAX_SLOTS`.
+const CHUNKS: usize = (usize::BITS - BASE.trailing_zeros()) as usize;
+
+/// The number of addressable slots, such that `index + BASE` cannot
+/// overflow.
+const MAX_SLOTS: usize = usize::MAX - BASE + 1;
+
+/// ID-indexed slots in chunks that are allocated on first use and never
+/// move, so that growing the store does not block readers and writers of
+/// other slots.
+struct Slots<K> {
+    chunks: [OnceLock<Box<[Slot<K>]>>; CHUNKS],
+}
+
+impl<K> Slots<K> {
+    fn new() -> Self {
+        Self { chunks: std::array::from_fn(|_| OnceLock::new()) }
+    }
+
+    /// The chunk holding `index` and the offset of `index` in it. Chunk `c`
+    /// holds `BASE << c` slots, starting at index `BASE * (2^c - 1)`.
+    fn locate(index: usize) -> (usize, usize) {
+        let n = index + BASE;
+        let chunk = (n.ilog2() - BASE.ilog2()) as usize;
+        (chunk, n - (BASE << chunk))
+    }
+
+    /// The slot of `index`, or `None` if its chunk was never allocated.
+    fn get(&self, index: usize) -> Option<&Slot<K>> {
+        let (chunk, offset) = Self::locate(index);
+        self.chunks[chunk].get().map(|chunk| &chunk[offset])
+    }
+
+    /// The slot of `index`, allocating its chunk if needed.
+    fn slot(&self, index: usize) -> &Slot<K> {
+        let (chunk, offset) = Self::locate(index);
+        let slots = self.chunks[chunk].get_or_init(|| (0..BASE << chunk).map(|_| RwLock::default()).collect());
+        &slots[offset]
+    }
+}
+
+/// A `CompactIdBiMap` that can be shared between threads and used through
+/// `&self`.
+///
+/// Keys are spread over independently locked shards, so threads interning
+/// different keys rarely contend, and lookups only take read locks. Keys are
+/// shared between their shard and the ID-indexed slots, hence `get_key`
+/// returns an `Arc`. Each slot is locked on its own and slots never move, so
+/// writers of different IDs do not contend there either. Freed IDs are
+/// reused most recently freed first.
+pub struct ConcurrentCompactIdBiMap<K, I = ID> {
+    shards: Box<[Shard<K, I>]>,
+    keys: Slots<K>,
+    /// The index of the next ID that has never been handed out.
+    next_new_index: AtomicUsize,
+    recycle_bin: Mutex<Vec<I>>,
+    hash_builder: DefaultHashBuilder,
+}
+
+impl<K, I> Default for ConcurrentCompactIdBiMap<K, I> where
+K: Hash + Eq,
+I: CompactId + Eq
+{
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<K, I> ConcurrentCompactIdBiMap<K, I> where
+K: Hash + Eq,
+I: CompactId + Eq
+{
+    /// Creates a map with a few shards per available CPU.
+    pub fn new() -> Self {
+        let cpus = thread::available_parallelism().map_or(1, NonZero::get);
+        Self::with_shards(4 * cpus)
+    }
+
+    /// Creates a map whose keys are spread over `shards` tables.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `shards` is zero.
+    pub fn with_shards(shards: usize) -> Self {
+        assert!(shards > 0, "a concurrent map needs at least one shard");
+        Self {
+            shards: (0..shards).map(|_| RwLock::default()).collect(),
+            keys: Slots::new(),
+            next_new_index: AtomicUsize::new(0),
+            recycle_bin: Mutex::default(),
+            hash_builder: DefaultHashBuilder::default(),
+        }
+    }
+
+    /// The shard responsible for keys with this hash. The low bits pick the
+    /// bucket inside the shard and the top ones its control byte, so the
+    /// shard is chosen by the bits in between.
+    fn shard(&self, hash: u64) -> &Shard<K, I> {
+        &self.shards[(hash >> 32) as usize % self.shards.len()]
+    }
+
+    pub fn get<Q>(&self, key: &Q) -> Option<I>
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Hash + Eq
+    {
+        let hash = self.hash_builder.hash_one(key);
+        let shard = self.shard(hash).read().unwrap_or_else(PoisonError::into_inner);
+        shard.find(hash, |(k, _)| (**k).borrow() == key).map(|&(_, id)| id)
+    }
+
+    pub fn get_key(&self, id: I) -> Option<Arc<K>> {
+        let slot = self.keys.get(id.to_index()?)?;
+        slot.read().unwrap_or_else(PoisonError::into_inner).clone()
+    }
+
+    /// Returns the ID of `k`, inserting it first if no thread has yet.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `k` is new and no ID is left; see `try_get_or_insert`.
+    pub fn get_or_insert(&self, k: K) -> I {
+        self.try_get_or_insert(k).unwrap_or_else(|err| panic!("{err}"))
+    }
+
+    /// Like `get_or_insert`, but returns an error instead of panicking if
+    /// `k` is new and no ID is left.
+    pub fn try_get_or_insert(&self, k: K) -> Result<I, Error> {
+        if let Some(id) = self.get(&k) {
+            return Ok(id);
+        }
+        let hash = self.hash_builder.hash_one(&k);
+        let mut shard = self.shard(hash).write().unwrap_or_else(PoisonError::into_inner);
+        // Another thread may have interned `k` since the read lock was let go.
+        if let Some(&(_, id)) = shard.find(hash, |(other, _)| **other == k) {
+            return Ok(id);
+        }
+        let id = self.fresh_id()?;
+        let k = Arc::new(k);
+        {
+            // Lock order: shard, then slot.
+            let slot = self.keys.slot(id.to_index().expect("fresh IDs have a slot"));
+            *slot.write().unwrap_or_else(PoisonError::into_inner) = Some(Arc::clone(&k));
+        }
+        shard.insert_unique(hash, (k, id), |(k, _)| self.hash_builder.hash_one(k));
+        Ok(id)
+    }
+
+    fn fresh_id(&self) -> Result<I, Error> {
+        if let Some(id) = self.recycle_bin.lock().unwrap_or_else(PoisonError::into_inner).pop() {
+            return Ok(id);
+        }
+        // Like `CompactIdMap`, never mint the last representable ID so that
+        // the next one is always representable too, nor one without a slot.
+        self.next_new_index
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |index| {
+                index.checked_add(1).filter(|&next| next < MAX_SLOTS && I::from_index(next).is_some())
+            })
+            .ok()
+            .and_then(I::from_index)
+            .ok_or(Error::IdSpaceExhausted)
+    }
+
+    /// Removes `k`, returning its ID, which becomes available for reuse.
+    pub fn remove<Q>(&self, k: &Q) -> Option<I>
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Hash + Eq
+    {
+        let hash = self.hash_builder.hash_one(k);
+        let mut shard = self.shard(hash).write().unwrap_or_else(PoisonError::into_inner);
+        let ((_, id), _) = shard.find_entry(hash, |(other, _)| (**other).borrow() == k).ok()?.remove();
+        self.free(id);
+        Some(id)
+    }
+
+    /// Removes the key of `id`, returning it, and makes `id` available for
+    /// reuse.
+    pub fn remove_id(&self, id: I) -> Option<Arc<K>> {
+        // Find the key first: its shard has to be locked before the slots.
+        let k = self.get_key(id)?;
+        let hash = self.hash_builder.hash_one(&*k);
+        let mut shard = self.shard(hash).write().unwrap_or_else(PoisonError::into_inner);
+        // Bail out if another thread removed `id` in the meantime, even if
+        // it was since reused.
+        shard.find_entry(hash, |(other, _)| Arc::ptr_eq(other, &k)).ok()?.remove();
+        self.free(id);
+        Some(k)
+    }
+
+    /// Empties the slot of a removed `id` and recycles it. The caller holds
+    /// the lock of the shard `id` was removed from.
+    fn free(&self, id: I) {
+        let slot = self.keys.get(id.to_index().expect("removed IDs have a slot"));
+        *slot.expect("removed IDs have a slot").write().unwrap_or_else(PoisonError::into_inner) = None;
+        self.recycle_bin.lock().unwrap_or_else(PoisonError::into_inner).push(id);
+    }
+}
+
+#[cfg(test)]
+mod test_concurrent {
+    use super::*;
+
+    #[test]
+    fn test_shared_interning() {
+        let symbols = ConcurrentCompactIdBiMap::<String, u32>::with_shards(4);
+        let ids: Vec<Vec<u32>> = thread::scope(|scope| {
+            let workers: Vec<_> = (0..8)
+                .map(|_| scope.spawn(|| (0..100).map(|n| symbols.get_or_insert(n.to_string())).collect()))
+                .collect();
+            workers.into_iter().map(|worker| worker.join().unwrap()).collect()
+        });
+        assert!(ids.iter().all(|worker| *worker == ids[0]));
+        let mut sorted = ids[0].clone();
+        sorted.sort();
+        assert_eq!((0..100).collect::<Vec<u32>>(), sorted);
+        for (n, &id) in ids[0].iter().enumerate() {
+            assert_eq!(n.to_string(), *symbols.get_key(id).unwrap());
+        }
+    }
+
+    #[test]
+    fn test_concurrent_removal() {
+        let symbols = ConcurrentCompactIdBiMap::<&str, u8>::with_shards(2);
+        let a = symbols.get_or_insert("a");
+        let b = symbols.get_or_insert("b");
+        assert_eq!(Some(a), symbols.remove("a"));
+        assert_eq!(None, symbols.remove("a"));
+        assert_eq!(a, symbols.get_or_insert("c"));
+        assert_eq!(Some(Arc::new("b")), symbols.remove_id(b));
+        assert_eq!(None, symbols.remove_id(b));
+        assert_eq!(None, symbols.get("b"));
+        assert_eq!(Some(a), symbols.get("c"));
+
+        let removed = thread::scope(|scope| {
+            let workers: Vec<_> = (0..4).map(|_| scope.spawn(|| symbols.remove_id(a))).collect();
+            workers.into_iter().filter_map(|worker| worker.join().unwrap()).count()
+        });
+        assert_eq!(1, removed);
+    }
+
+    #[test]
+    fn test_slot_chunks() {
+        assert_eq!((0, 0), Slots::<()>::locate(0));
+        assert_eq!((0, BASE - 1), Slots::<()>::locate(BASE - 1));
+        assert_eq!((1, 0), Slots::<()>::locate(BASE));
+        assert_eq!((2, 0), Slots::<()>::locate(3 * BASE));
+        assert_eq!((CHUNKS - 1, (BASE << (CHUNKS - 1)) - 1), Slots::<()>::locate(MAX_SLOTS - 1));
+
+        let slots = Slots::<u8>::new();
+        assert!(slots.get(100).is_none());
+        *slots.slot(100).write().unwrap() = Some(Arc::new(1));
+        assert_eq!(Some(Arc::new(1)), *slots.get(100).unwrap().read().unwrap());
+        assert!(slots.get(BASE - 1).is_none());
+    }
+}
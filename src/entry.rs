@@ -0,0 +1,224 @@
+use std::marker::PhantomData;
+
+use hashbrown::hash_table;
+
+use crate::{CompactId, CompactIdMap, Error, Slot};
+
+/// A view into a single key of a `CompactIdBiMap` or `CompactIdValueMap`,
+/// obtained from `entry`. The key has been hashed exactly once.
+pub enum Entry<'a, K, I, T = K> {
+    Occupied(OccupiedEntry<'a, K, I, T>),
+    Vacant(VacantEntry<'a, K, I, T>),
+}
+
+/// An interned key.
+pub struct OccupiedEntry<'a, K, I, T = K> {
+    pub(crate) index: hash_table::OccupiedEntry<'a, I>,
+    pub(crate) map: &'a mut CompactIdMap<I, T>,
+    pub(crate) _key: PhantomData<fn() -> K>,
+}
+
+/// A key that is not interned yet.
+pub struct VacantEntry<'a, K, I, T = K> {
+    pub(crate) index: hash_table::VacantEntry<'a, I>,
+    pub(crate) map: &'a mut CompactIdMap<I, T>,
+    pub(crate) key: K,
+}
+
+impl<'a, K, I: CompactId, T: Slot<K>> Entry<'a, K, I, T> {
+    /// The ID of the key, or the ID it will get if inserted now. Fails like
+    /// `VacantEntry::id` if the key is vacant and no ID is left.
+    pub fn id(&self) -> Result<I, Error> {
+        match self {
+            Entry::Occupied(entry) => Ok(entry.id()),
+            Entry::Vacant(entry) => entry.id(),
+        }
+    }
+
+    pub fn key(&self) -> &K {
+        match self {
+            Entry::Occupied(entry) => entry.key(),
+            Entry::Vacant(entry) => entry.key(),
+        }
+    }
+}
+
+impl<'a, K, I: CompactId> Entry<'a, K, I> {
+    /// Returns the ID of the key, inserting it first if it is vacant.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the key is vacant and no ID is left.
+    pub fn or_insert(self) -> I {
+        match self {
+            Entry::Occupied(entry) => entry.id(),
+            Entry::Vacant(entry) => entry.insert(),
+        }
+    }
+
+    /// Like `or_insert`, but calls `f` with the freshly assigned ID when the
+    /// key is inserted, e.g. to initialize side tables indexed by ID.
+    pub fn or_insert_with<F: FnOnce(I)>(self, f: F) -> I {
+        match self {
+            Entry::Occupied(entry) => entry.id(),
+            Entry::Vacant(entry) => {
+                let id = entry.insert();
+                f(id);
+                id
+            }
+        }
+    }
+}
+
+impl<'a, K, V, I: CompactId> Entry<'a, K, I, (K, V)> {
+    /// Returns the ID of the key and its value, inserting it with `v` first
+    /// if it is vacant.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the key is vacant and no ID is left.
+    pub fn or_insert(self, v: V) -> (I, &'a mut V) {
+        self.or_insert_with(|_| v)
+    }
+
+    /// Like `or_insert`, but only builds the value when the key is inserted,
+    /// passing it the freshly assigned ID.
+    pub fn or_insert_with<F: FnOnce(I) -> V>(self, f: F) -> (I, &'a mut V) {
+        match self {
+            Entry::Occupied(entry) => (entry.id(), entry.into_mut()),
+            Entry::Vacant(entry) => {
+                let id = entry.id().unwrap_or_else(|err| panic!("{err}"));
+                entry.insert(f(id))
+            }
+        }
+    }
+}
+
+impl<'a, K, I: CompactId, T: Slot<K>> OccupiedEntry<'a, K, I, T> {
+    pub fn id(&self) -> I {
+        *self.index.get()
+    }
+
+    /// The interned key, which may be a different (but equal) value from the
+    /// one passed to `entry`.
+    pub fn key(&self) -> &K {
+        self.map.occupied(self.id()).key()
+    }
+
+    /// Removes the key, recycling its ID, and returns what its slot held.
+    pub fn remove(self) -> T {
+        let (id, _) = self.index.remove();
+        self.map.remove_id(id).expect("reverse index refers to an empty slot")
+    }
+}
+
+impl<'a, K, V, I: CompactId> OccupiedEntry<'a, K, I, (K, V)> {
+    pub fn get(&self) -> &V {
+        &self.map.occupied(self.id()).1
+    }
+
+    pub fn get_mut(&mut self) -> &mut V {
+        let id = self.id();
+        &mut self.map.get_mut(id).expect("reverse index refers to an empty slot").1
+    }
+
+    /// The value, borrowed for as long as the map was.
+    pub fn into_mut(self) -> &'a mut V {
+        let id = self.id();
+        &mut self.map.get_mut(id).expect("reverse index refers to an empty slot").1
+    }
+
+    /// Replaces the value, returning the old one. Unlike writing through
+    /// `get_mut`, this is undone by rolling back a checkpoint.
+    pub fn insert(&mut self, v: V) -> V {
+        let id = self.id();
+        self.map.update(id, |(_, value)| std::mem::replace(value, v))
+    }
+}
+
+impl<'a, K, I: CompactId, T> VacantEntry<'a, K, I, T> {
+    /// The ID the key will get when inserted, or `Error::IdSpaceExhausted`
+    /// or `Error::IdLimitReached` if no ID is left.
+    pub fn id(&self) -> Result<I, Error> {
+        self.map.peek_fresh_id()
+    }
+
+    pub fn key(&self) -> &K {
+        &self.key
+    }
+
+    pub fn into_key(self) -> K {
+        self.key
+    }
+}
+
+impl<'a, K, I: CompactId> VacantEntry<'a, K, I> {
+    /// Interns the key and returns its new ID.
+    ///
+    /// # Panics
+    ///
+    /// Panics if no ID is left; see `try_insert`.
+    pub fn insert(self) -> I {
+        self.try_insert().unwrap_or_else(|err| panic!("{err}"))
+    }
+
+    /// Interns the key and returns its new ID. If no ID is left, returns
+    /// `Error::IdSpaceExhausted` or `Error::IdLimitReached`, as
+    /// `CompactIdMap::try_insert` does.
+    pub fn try_insert(self) -> Result<I, Error> {
+        let id = self.map.try_insert(self.key)?;
+        self.index.insert(id);
+        Ok(id)
+    }
+}
+
+impl<'a, K, V, I: CompactId> VacantEntry<'a, K, I, (K, V)> {
+    /// Interns the key with the value `v`, returning the new ID and the
+    /// stored value.
+    ///
+    /// # Panics
+    ///
+    /// Panics if no ID is left; see `try_insert`.
+    pub fn insert(self, v: V) -> (I, &'a mut V) {
+        self.try_insert(v).unwrap_or_else(|err| panic!("{err}"))
+    }
+
+    /// Like `insert`, but returns `Error::IdSpaceExhausted` or
+    /// `Error::IdLimitReached` if no ID is left.
+    pub fn try_insert(self, v: V) -> Result<(I, &'a mut V), Error> {
+        let id = self.map.try_insert((self.key, v))?;
+        self.index.insert(id);
+        Ok((id, &mut self.map.get_mut(id).expect("just inserted").1))
+    }
+}
+
+/// A slot of a `CompactIdMap` whose ID is known before its value is inserted,
+/// obtained from `CompactIdMap::vacant_entry`.
+pub struct VacantIdEntry<'a, I, K> {
+    pub(crate) map: &'a mut CompactIdMap<I, K>,
+}
+
+impl<'a, I: CompactId, K> VacantIdEntry<'a, I, K> {
+    /// The ID the value will get when inserted, so that it can be embedded in
+    /// the value itself, or `Error::IdSpaceExhausted` or
+    /// `Error::IdLimitReached` if no ID is left.
+    pub fn id(&self) -> Result<I, Error> {
+        self.map.peek_fresh_id()
+    }
+
+    /// Inserts the value under the reserved ID.
+    ///
+    /// # Panics
+    ///
+    /// Panics if no ID is left; see `try_insert`.
+    pub fn insert(self, k: K) -> &'a mut K {
+        self.try_insert(k).unwrap_or_else(|err| panic!("{err}"))
+    }
+
+    /// Like `insert`, but returns the error `id` reports instead of
+    /// panicking if no ID is left.
+    pub fn try_insert(self, k: K) -> Result<&'a mut K, Error> {
+        let id = self.map.try_insert(k)?;
+        Ok(self.map.get_mut(id).expect("just inserted"))
+    }
+}
@@ -0,0 +1,68 @@
+use std::fmt;
+
+/// Errors reported by the maps in this crate. IDs are reported by their slot
+/// index so that the error type does not depend on the ID type.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Error {
+    /// No further ID can be minted: the next one is not representable in the
+    /// ID type.
+    IdSpaceExhausted,
+    /// No further ID can be minted: the maximum ID set with `with_max_id` has
+    /// been handed out.
+    IdLimitReached,
+    /// An ID that cannot own a slot, such as a negative integer.
+    InvalidId,
+    /// A slot or recycled ID at or above `next_new_id`.
+    IdOutOfRange { index: usize },
+    /// An ID listed more than once in the recycle bin.
+    DuplicateRecycledId { index: usize },
+    /// A recycled ID whose slot is still occupied.
+    RecycledIdInUse { index: usize },
+    /// A slot or `next_new_id` above the maximum ID set with `with_max_id`.
+    IdAboveLimit { index: usize },
+    /// A vacant slot below `next_new_id` that is missing from the recycle
+    /// bin, so it would never be reused.
+    LeakedId { index: usize },
+    /// A recycle bin that is not in ascending order under
+    /// `RecyclePolicy::LowestFirst`.
+    UnsortedRecycleBin,
+    /// A recycled ID under `RecyclePolicy::Never`.
+    RecycledIdWithoutRecycling { index: usize },
+    /// A reference count that is zero for a live ID or non-zero for a free
+    /// one.
+    InvalidRefCount { index: usize },
+    /// A key stored in more than one slot of a bimap.
+    DuplicateKey { index: usize },
+    /// A retired slot, one whose generation would wrap around, that is live
+    /// or recycled.
+    RetiredIdInUse { index: usize },
+    /// Slot generations given for an ID type that is not generational.
+    UnexpectedGenerations,
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::IdSpaceExhausted => write!(f, "ID space exhausted"),
+            Error::IdLimitReached => write!(f, "maximum ID reached"),
+            Error::InvalidId => write!(f, "ID has no slot index"),
+            Error::IdOutOfRange { index } => write!(f, "ID {index} is not below next_new_id"),
+            Error::DuplicateRecycledId { index } => write!(f, "ID {index} is recycled more than once"),
+            Error::RecycledIdInUse { index } => write!(f, "recycled ID {index} is still in use"),
+            Error::IdAboveLimit { index } => write!(f, "ID {index} is above the maximum ID"),
+            Error::LeakedId { index } => write!(f, "free ID {index} is missing from the recycle bin"),
+            Error::UnsortedRecycleBin => write!(f, "recycle bin is not sorted lowest first"),
+            Error::RecycledIdWithoutRecycling { index } => {
+                write!(f, "ID {index} is recycled although the policy never recycles")
+            }
+            Error::InvalidRefCount { index } => {
+                write!(f, "reference count of ID {index} does not match its slot")
+            }
+            Error::DuplicateKey { index } => write!(f, "key of ID {index} is already used by a lower ID"),
+            Error::RetiredIdInUse { index } => write!(f, "retired ID {index} is still in use"),
+            Error::UnexpectedGenerations => write!(f, "generations given for IDs that are not generational"),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
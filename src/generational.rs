@@ -0,0 +1,181 @@
+use serde::{Deserialize, Serialize};
+
+use crate::{CompactId, CompactIdBiMap, CompactIdMap, ID};
+
+/// An ID paired with the generation of its slot at the time it was handed
+/// out. Once the ID is removed and its slot recycled, the old handle no
+/// longer matches and is rejected instead of resolving to the new entry.
+///
+/// The maps in this crate track generations for every ID type implementing
+/// `CompactId` with `GENERATIONAL` set, as this one does. A slot whose
+/// generation would wrap around is retired and never handed out again.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
+pub struct GenerationalId<I = ID> {
+    index: I,
+    generation: u32,
+}
+
+impl<I: Copy> GenerationalId<I> {
+    /// The plain ID of the slot, shared by every generation of it.
+    pub fn index(self) -> I {
+        self.index
+    }
+
+    pub fn generation(self) -> u32 {
+        self.generation
+    }
+}
+
+impl<I: CompactId> CompactId for GenerationalId<I> {
+    const GENERATIONAL: bool = true;
+
+    #[inline]
+    fn from_index(index: usize) -> Option<Self> {
+        Some(Self { index: I::from_index(index)?, generation: 0 })
+    }
+
+    #[inline]
+    fn to_index(self) -> Option<usize> {
+        self.index.to_index()
+    }
+
+    #[inline]
+    fn generation(self) -> u32 {
+        self.generation
+    }
+
+    #[inline]
+    fn with_generation(self, generation: u32) -> Self {
+        Self { generation, ..self }
+    }
+}
+
+/// A `CompactIdBiMap` handing out `GenerationalId`s, so that IDs kept from
+/// before a `remove`/`remove_id` do not resolve to whatever key reuses the
+/// slot.
+pub type GenerationalIdBiMap<K, I = ID> = CompactIdBiMap<K, GenerationalId<I>>;
+
+/// A `CompactIdMap` handing out `GenerationalId`s, so that IDs kept from
+/// before a `remove_id` do not resolve to whatever value reuses the slot.
+pub type GenerationalIdMap<I, K> = CompactIdMap<GenerationalId<I>, K>;
+
+#[cfg(test)]
+mod test_generational {
+    use super::*;
+    use crate::{Entry, Error};
+
+    #[test]
+    fn test_stale_bimap_ids() {
+        let mut ids = GenerationalIdBiMap::<String>::new();
+        let hello = ids.insert(String::from("hello"));
+        assert_eq!(Some(hello), ids.remove("hello"));
+        let are = ids.insert(String::from("are"));
+        assert_eq!(hello.index(), are.index());
+        assert_ne!(hello, are);
+        assert_eq!(None, ids.get_key(hello));
+        assert_eq!(None, ids.remove_id(hello));
+        assert_eq!(Some(&String::from("are")), ids.get_key(are));
+        assert_eq!(Some(are), ids.get("are"));
+        assert_eq!(Some(String::from("are")), ids.remove_id(are));
+    }
+
+    #[test]
+    fn test_stale_map_ids() {
+        let mut map = GenerationalIdMap::<u16, &str>::new();
+        let a = map.insert("a");
+        assert_eq!(Some("a"), map.remove_id(a));
+        let b = map.insert("b");
+        assert_eq!(a.index(), b.index());
+        assert_eq!(None, map.get(a));
+        assert_eq!(None, map.get_mut(a));
+        assert_eq!(None, map.remove_id(a));
+        assert_eq!(Some(&"b"), map.get(b));
+    }
+
+    #[test]
+    fn test_generational_api() {
+        let mut ids = GenerationalIdBiMap::<&str, u8>::new();
+        let a = ids.entry("a").or_insert();
+        let b = ids.get_or_insert("b");
+        assert_eq!(vec![(a, &"a"), (b, &"b")], ids.iter().collect::<Vec<_>>());
+        ids.remove_id(a);
+        match ids.entry("c") {
+            Entry::Vacant(entry) => assert_eq!(a.index(), entry.id().unwrap().index()),
+            Entry::Occupied(_) => unreachable!(),
+        }
+        let checkpoint = ids.checkpoint();
+        let c = ids.insert("c");
+        assert_eq!((a.index(), 1), (c.index(), c.generation()));
+        ids.rollback(checkpoint);
+        assert_eq!(None, ids.get_key(c));
+
+        let remap = ids.compact();
+        let b2 = remap.get(b).unwrap();
+        assert_eq!(None, remap.get(a));
+        assert_eq!(Some(&"b"), ids.get_key(b2));
+        assert_eq!(None, ids.get_key(b));
+        assert_eq!(Ok(()), ids.validate());
+
+        let drained = ids.drain().collect::<Vec<_>>();
+        assert_eq!(vec![(b2, "b")], drained);
+        let d = ids.insert("d");
+        assert_eq!(b2.index(), d.index());
+        assert_eq!(None, ids.get_key(b2));
+
+        let json = serde_json::to_string(&ids).unwrap();
+        let loaded: GenerationalIdBiMap<&str, u8> = serde_json::from_str(&json).unwrap();
+        assert_eq!(Some(&"d"), loaded.get_key(d));
+        assert_eq!(None, loaded.get_key(b2));
+    }
+
+    #[test]
+    fn test_retired_slots() {
+        let max = u32::MAX - 1;
+        let json = format!(r#"{{"keys":["a","b"],"policy":"Lifo","generations":[{max}]}}"#);
+        let mut map: GenerationalIdMap<u8, &str> = serde_json::from_str(&json).unwrap();
+        let (a, b) = (map.ids().next().unwrap(), map.ids().nth(1).unwrap());
+        assert_eq!(max, a.generation());
+
+        let checkpoint = map.checkpoint();
+        assert_eq!(Some("a"), map.remove_id(a));
+        map.rollback(checkpoint);
+        assert_eq!(Some(&"a"), map.get(a));
+
+        // The slot would wrap around, so it is retired instead of recycled.
+        assert_eq!(Some("a"), map.remove_id(a));
+        let c = map.insert("c");
+        assert_eq!(2, c.index());
+        assert_eq!(Ok(()), map.validate());
+
+        let remap = map.compact();
+        let (b2, c2) = (remap.get(b).unwrap(), remap.get(c).unwrap());
+        assert_eq!((1, 2), (b2.index(), c2.index()));
+        map.remove_id(b2);
+        map.remove_id(c2);
+        map.shrink_to_fit();
+        assert_eq!(1, map.insert("d").index());
+
+        let json = serde_json::to_string(&map).unwrap();
+        let loaded: GenerationalIdMap<u8, &str> = serde_json::from_str(&json).unwrap();
+        assert_eq!(Ok(()), loaded.validate());
+        assert_eq!(2, loaded.clone().insert("e").index());
+
+        let in_use = r#"{"keys":["a"],"generations":[4294967295]}"#;
+        let err = serde_json::from_str::<GenerationalIdMap<u8, &str>>(in_use).unwrap_err();
+        assert!(err.to_string().contains(&Error::RetiredIdInUse { index: 0 }.to_string()));
+        let plain = r#"{"keys":["a"],"generations":[1]}"#;
+        assert!(serde_json::from_str::<CompactIdMap<u8, &str>>(plain).is_err());
+    }
+
+    #[test]
+    fn test_rollback_restores_generations() {
+        let mut map = GenerationalIdMap::<u8, &str>::new();
+        let a = map.insert("a");
+        let before = serde_json::to_string(&map).unwrap();
+        let checkpoint = map.checkpoint();
+        map.remove_id(a);
+        map.rollback(checkpoint);
+        assert_eq!(before, serde_json::to_string(&map).unwrap());
+        assert_eq!(Some(&"a"), map.get(a));
+    }
+}
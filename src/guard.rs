@@ -0,0 +1,316 @@
+use std::{
+    cell::RefCell,
+    hash::{BuildHasher, Hash},
+    rc::Rc,
+    sync::{Arc, Mutex, PoisonError, RwLock},
+};
+
+use crate::{
+    CompactId, CompactIdBiMap, CompactIdMap, CompactIdValueMap, RefCountedIdBiMap, Slot,
+};
+
+/// A map that can take back an ID, as done by an `IdGuard` when dropped.
+///
+/// As the ID may have been removed and reused by the time it is released,
+/// the map first describes what the ID refers to with a token, and only
+/// releases the ID while it still refers to the same thing.
+pub trait ReleaseId<I> {
+    /// What identifies the entry of an ID beyond the ID itself.
+    type Token;
+
+    /// The token for live ID `id`, or `None` if `id` is not live.
+    fn token(&self, id: I) -> Option<Self::Token>;
+
+    /// Releases `id` if it still refers to the entry `token` was taken for.
+    fn release_id(&mut self, id: I, token: &Self::Token);
+}
+
+/// The token is a copy of the value, so an ID now holding a different value
+/// is left alone. Generational IDs also tell a reused slot apart by its
+/// generation; plain IDs cannot, so a slot reused for an equal value is
+/// released all the same.
+impl<I, K> ReleaseId<I> for CompactIdMap<I, K> where
+I: CompactId,
+K: Clone + PartialEq
+{
+    type Token = K;
+
+    fn token(&self, id: I) -> Option<K> {
+        self.get(id).cloned()
+    }
+
+    fn release_id(&mut self, id: I, value: &K) {
+        if self.get(id) == Some(value) {
+            self.remove_id(id);
+        }
+    }
+}
+
+/// The token is the key, so an ID now holding another key is left alone.
+impl<K, I, S, T> ReleaseId<I> for CompactIdBiMap<K, I, S, T> where
+K: Hash + Eq + Clone,
+I: CompactId + Eq,
+S: BuildHasher,
+T: Slot<K>
+{
+    type Token = K;
+
+    fn token(&self, id: I) -> Option<K> {
+        self.get_key(id).cloned()
+    }
+
+    fn release_id(&mut self, id: I, key: &K) {
+        if self.get(key) == Some(id) {
+            self.remove_id(id);
+        }
+    }
+}
+
+impl<K, V, I, S> ReleaseId<I> for CompactIdValueMap<K, V, I, S> where
+K: Hash + Eq + Clone,
+I: CompactId + Eq,
+S: BuildHasher
+{
+    type Token = K;
+
+    fn token(&self, id: I) -> Option<K> {
+        (**self).token(id)
+    }
+
+    fn release_id(&mut self, id: I, key: &K) {
+        (**self).release_id(id, key);
+    }
+}
+
+/// Gives back one reference, so the ID is only recycled once no other guard
+/// or caller holds it. Like for bimaps, the token is the key.
+impl<K, I> ReleaseId<I> for RefCountedIdBiMap<K, I> where
+K: Hash + Eq + Clone,
+I: CompactId + Eq
+{
+    type Token = K;
+
+    fn token(&self, id: I) -> Option<K> {
+        self.get_key(id).cloned()
+    }
+
+    fn release_id(&mut self, id: I, key: &K) {
+        if self.get(key) == Some(id) {
+            self.release(id);
+        }
+    }
+}
+
+/// A shared handle to a map that guards keep alive, such as
+/// `Rc<RefCell<CompactIdBiMap<..>>>` or `Arc<Mutex<GenerationalIdMap<..>>>`.
+pub trait SharedIdMap<I> {
+    type Token;
+
+    /// The token for live ID `id`; see `ReleaseId::token`.
+    fn token(&self, id: I) -> Option<Self::Token>;
+
+    fn release_id(&self, id: I, token: &Self::Token);
+}
+
+impl<I, M: ReleaseId<I>> SharedIdMap<I> for Rc<RefCell<M>> {
+    type Token = M::Token;
+
+    /// # Panics
+    ///
+    /// Panics if the map is mutably borrowed.
+    fn token(&self, id: I) -> Option<M::Token> {
+        self.borrow().token(id)
+    }
+
+    /// If the map is borrowed, e.g. by the code a panic is unwinding
+    /// through, the ID is left in the map instead, as panicking again would
+    /// abort the process.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the map is borrowed and the thread is not panicking
+    /// already.
+    fn release_id(&self, id: I, token: &M::Token) {
+        match self.try_borrow_mut() {
+            Ok(mut map) => map.release_id(id, token),
+            Err(_) if std::thread::panicking() => {}
+            Err(err) => panic!("cannot release an ID of a borrowed map: {err}"),
+        }
+    }
+}
+
+impl<I, M: ReleaseId<I>> SharedIdMap<I> for Arc<Mutex<M>> {
+    type Token = M::Token;
+
+    fn token(&self, id: I) -> Option<M::Token> {
+        self.lock().unwrap_or_else(PoisonError::into_inner).token(id)
+    }
+
+    fn release_id(&self, id: I, token: &M::Token) {
+        // A panic elsewhere doesn't make the ID any less free.
+        self.lock().unwrap_or_else(PoisonError::into_inner).release_id(id, token);
+    }
+}
+
+impl<I, M: ReleaseId<I>> SharedIdMap<I> for Arc<RwLock<M>> {
+    type Token = M::Token;
+
+    fn token(&self, id: I) -> Option<M::Token> {
+        self.read().unwrap_or_else(PoisonError::into_inner).token(id)
+    }
+
+    fn release_id(&self, id: I, token: &M::Token) {
+        self.write().unwrap_or_else(PoisonError::into_inner).release_id(id, token);
+    }
+}
+
+/// Owns an ID of a shared map and removes it from the map when dropped, so
+/// the entry cannot outlive its owner.
+///
+/// The guard remembers the entry it owns (its key, or for a `CompactIdMap`
+/// its value), so if the ID was removed and reused by another entry in the
+/// meantime, dropping the guard leaves that entry alone. A `CompactIdMap`
+/// with plain IDs cannot tell an entry from a later one with an equal value
+/// in the same slot; use `GenerationalIdMap` where that matters.
+pub struct IdGuard<M, I>
+    where M: SharedIdMap<I>, I: Copy
+{
+    /// `None` once the ID has been taken out with `into_id`.
+    map: Option<M>,
+    id: I,
+    token: M::Token,
+}
+
+impl<M, I> IdGuard<M, I> where
+M: SharedIdMap<I>,
+I: Copy
+{
+    /// Takes ownership of `id`, a live ID of `map`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `id` is not live in `map`.
+    pub fn new(map: M, id: I) -> Self {
+        let token = map.token(id).expect("guarded IDs are live");
+        Self { map: Some(map), id, token }
+    }
+
+    pub fn id(&self) -> I {
+        self.id
+    }
+
+    pub fn map(&self) -> &M {
+        self.map.as_ref().expect("only taken by into_id")
+    }
+
+    /// Disarms the guard and returns its ID, which the caller must now
+    /// remove by hand.
+    pub fn into_id(mut self) -> I {
+        self.map = None;
+        self.id
+    }
+}
+
+impl<M, I> Drop for IdGuard<M, I> where
+M: SharedIdMap<I>,
+I: Copy
+{
+    fn drop(&mut self) {
+        if let Some(map) = self.map.take() {
+            map.release_id(self.id, &self.token);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test_guard {
+    use super::*;
+    use crate::GenerationalIdMap;
+
+    #[test]
+    fn test_rc_guard() {
+        let names = Rc::new(RefCell::new(CompactIdBiMap::<String>::new()));
+        let id = names.borrow_mut().insert(String::from("a"));
+        let guard = IdGuard::new(Rc::clone(&names), id);
+        assert_eq!(Some(id), names.borrow().get("a"));
+        drop(guard);
+        assert_eq!(None, names.borrow().get("a"));
+        assert_eq!(id, names.borrow_mut().insert(String::from("b")));
+
+        let kept = IdGuard::new(Rc::clone(&names), id).into_id();
+        assert_eq!(Some(&String::from("b")), names.borrow().get_key(kept));
+        assert_eq!(1, Rc::strong_count(&names));
+    }
+
+    #[test]
+    fn test_shared_guards() {
+        let symbols = Arc::new(Mutex::new(RefCountedIdBiMap::<&str, u32>::new()));
+        let id = symbols.lock().unwrap().get_or_insert("x");
+        let first = IdGuard::new(Arc::clone(&symbols), id);
+        let again = symbols.lock().unwrap().get_or_insert("x");
+        let second = IdGuard::new(Arc::clone(&symbols), again);
+        drop(first);
+        assert_eq!(Some(id), symbols.lock().unwrap().get("x"));
+        drop(second);
+        assert_eq!(None, symbols.lock().unwrap().get("x"));
+
+        let values = Arc::new(RwLock::new(GenerationalIdMap::<u8, char>::new()));
+        let id = values.write().unwrap().insert('v');
+        std::thread::spawn({
+            let guard = IdGuard::new(Arc::clone(&values), id);
+            move || assert_eq!(id, guard.id())
+        })
+        .join()
+        .unwrap();
+        assert_eq!(None, values.read().unwrap().get(id));
+    }
+
+    #[test]
+    fn test_reused_ids() {
+        let names = Rc::new(RefCell::new(CompactIdBiMap::<&str, u8>::new()));
+        let a = names.borrow_mut().insert("a");
+        let guard = IdGuard::new(Rc::clone(&names), a);
+        names.borrow_mut().remove("a");
+        assert_eq!(a, names.borrow_mut().insert("b"));
+        drop(guard);
+        assert_eq!(Some(a), names.borrow().get("b"));
+
+        let values = Arc::new(Mutex::new(GenerationalIdMap::<u8, char>::new()));
+        let v = values.lock().unwrap().insert('v');
+        let guard = IdGuard::new(Arc::clone(&values), v);
+        values.lock().unwrap().remove_id(v);
+        let w = values.lock().unwrap().insert('v');
+        assert_eq!(v.index(), w.index());
+        drop(guard);
+        assert_eq!(Some(&'v'), values.lock().unwrap().get(w));
+    }
+
+    #[test]
+    fn test_plain_map_guard() {
+        let tasks = Rc::new(RefCell::new(CompactIdMap::<u32, &str>::new()));
+        let a = tasks.borrow_mut().insert("a");
+        drop(IdGuard::new(Rc::clone(&tasks), a));
+        assert_eq!(None, tasks.borrow().get(a));
+
+        let b = tasks.borrow_mut().insert("b");
+        let guard = IdGuard::new(Rc::clone(&tasks), b);
+        tasks.borrow_mut().remove_id(b);
+        assert_eq!(b, tasks.borrow_mut().insert("c"));
+        drop(guard);
+        assert_eq!(Some(&"c"), tasks.borrow().get(b));
+    }
+
+    #[test]
+    fn test_guard_dropped_while_unwinding() {
+        let names = Rc::new(RefCell::new(CompactIdBiMap::<&str, u8>::new()));
+        let a = names.borrow_mut().insert("a");
+        let guard = IdGuard::new(Rc::clone(&names), a);
+        let unwound = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            let _borrow = names.borrow();
+            let _guard = guard;
+            panic!("unwinding with the map borrowed");
+        }));
+        assert!(unwound.is_err());
+        assert_eq!(Some(a), names.borrow().get("a"));
+    }
+}
@@ -0,0 +1,177 @@
+/// An ID type the maps in this crate can hand out.
+///
+/// IDs are minted densely: the `n`-th fresh ID is `from_index(n)`, and every
+/// ID owns the slot at its index. Minting fails (and the maps panic, like an
+/// integer overflow) once `from_index` can no longer represent the next
+/// index.
+pub trait CompactId: Copy {
+    /// The ID owning slot `index`, or `None` if it is not representable.
+    fn from_index(index: usize) -> Option<Self>;
+
+    /// The slot index of this ID, or `None` if it cannot have a slot (e.g. a
+    /// negative integer).
+    fn to_index(self) -> Option<usize>;
+
+    /// Whether IDs carry the generation of their slot, so that maps can tell
+    /// an ID kept from before its slot was reused from the current one. See
+    /// `GenerationalId`.
+    const GENERATIONAL: bool = false;
+
+    /// The generation of the slot this ID was handed out for; always 0 for
+    /// IDs that are not generational.
+    #[inline]
+    fn generation(self) -> u32 {
+        0
+    }
+
+    /// This ID for generation `generation` of its slot. IDs that are not
+    /// generational ignore it.
+    #[inline]
+    fn with_generation(self, generation: u32) -> Self {
+        let _ = generation;
+        self
+    }
+}
+
+macro_rules! impl_compact_id {
+    ($($t:ty),*) => {
+        $(
+            impl CompactId for $t {
+                #[inline]
+                fn from_index(index: usize) -> Option<Self> {
+                    index.try_into().ok()
+                }
+
+                #[inline]
+                fn to_index(self) -> Option<usize> {
+                    self.try_into().ok()
+                }
+            }
+        )*
+    };
+}
+
+impl_compact_id!(u8, u16, u32, u64, u128, usize, i8, i16, i32, i64, i128, isize);
+
+/// Defines a typed ID newtype usable with every map in this crate.
+///
+/// `define_id!(pub struct NodeId(u32));` generates a `NodeId` backed by a
+/// `NonZero<u32>` holding `index + 1`, so `Option<NodeId>` is the same size as
+/// `NodeId`. The largest value of the underlying integer is therefore not a
+/// valid index. The generated type implements `CompactId`, the usual value
+/// traits, and serializes as its plain index.
+///
+/// ```
+/// compact_id_map::define_id!(pub struct NodeId(u32));
+///
+/// let mut nodes = compact_id_map::CompactIdMap::<NodeId, &str>::new();
+/// let root = nodes.insert("root");
+/// assert_eq!(0, root.index());
+/// assert_eq!(Some(&"root"), nodes.get(root));
+/// assert_eq!(std::mem::size_of::<NodeId>(), std::mem::size_of::<Option<NodeId>>());
+/// ```
+#[macro_export]
+macro_rules! define_id {
+    ($(#[$meta:meta])* $vis:vis struct $name:ident($repr:ty) $(;)?) => {
+        $(#[$meta])*
+        #[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+        #[repr(transparent)]
+        $vis struct $name(::core::num::NonZero<$repr>);
+
+        impl $name {
+            /// The ID with the given index, or `None` for the largest value
+            /// of the underlying integer, which has no non-zero encoding.
+            #[inline]
+            pub fn new(index: $repr) -> ::core::option::Option<Self> {
+                index.checked_add(1).and_then(::core::num::NonZero::new).map(Self)
+            }
+
+            #[inline]
+            pub fn index(self) -> $repr {
+                self.0.get() - 1
+            }
+        }
+
+        impl $crate::CompactId for $name {
+            #[inline]
+            fn from_index(index: usize) -> ::core::option::Option<Self> {
+                <$repr>::try_from(index).ok().and_then(Self::new)
+            }
+
+            #[inline]
+            fn to_index(self) -> ::core::option::Option<usize> {
+                usize::try_from(self.index()).ok()
+            }
+        }
+
+        impl ::core::fmt::Debug for $name {
+            fn fmt(&self, f: &mut ::core::fmt::Formatter<'_>) -> ::core::fmt::Result {
+                f.debug_tuple(stringify!($name)).field(&self.index()).finish()
+            }
+        }
+
+        impl $crate::__serde::Serialize for $name {
+            fn serialize<S>(&self, serializer: S) -> ::core::result::Result<S::Ok, S::Error>
+            where
+                S: $crate::__serde::Serializer,
+            {
+                $crate::__serde::Serialize::serialize(&self.index(), serializer)
+            }
+        }
+
+        impl<'de> $crate::__serde::Deserialize<'de> for $name {
+            fn deserialize<D>(deserializer: D) -> ::core::result::Result<Self, D::Error>
+            where
+                D: $crate::__serde::Deserializer<'de>,
+            {
+                let index = <$repr as $crate::__serde::Deserialize>::deserialize(deserializer)?;
+                Self::new(index).ok_or_else(|| {
+                    <D::Error as $crate::__serde::de::Error>::custom(concat!(
+                        "index out of range for ",
+                        stringify!($name)
+                    ))
+                })
+            }
+        }
+    };
+}
+
+#[cfg(test)]
+mod test_define_id {
+    use crate::{CompactIdBiMap, CompactIdMap};
+
+    define_id!(
+        /// IDs of test nodes.
+        struct NodeId(u8);
+    );
+
+    #[test]
+    fn test_typed_ids() {
+        assert_eq!(size_of::<NodeId>(), size_of::<Option<NodeId>>());
+        assert_eq!(None, NodeId::new(u8::MAX));
+
+        let mut nodes = CompactIdMap::<NodeId, char>::new();
+        let a = nodes.insert('a');
+        let b = nodes.insert('b');
+        assert_eq!((0, 1), (a.index(), b.index()));
+        assert_eq!(Some('a'), nodes.remove_id(a));
+        assert_eq!(a, nodes.insert('c'));
+
+        let mut names = CompactIdBiMap::<&str, NodeId>::new();
+        let x = names.insert("x");
+        assert_eq!(Some(x), names.get("x"));
+        assert_eq!("NodeId(0)", format!("{x:?}"));
+        assert_eq!("0", serde_json::to_string(&x).unwrap());
+        assert_eq!(x, serde_json::from_str("0").unwrap());
+        assert!(serde_json::from_str::<NodeId>("255").is_err());
+    }
+
+    #[test]
+    #[should_panic(expected = "ID space exhausted")]
+    fn test_typed_id_overflow() {
+        let mut nodes = CompactIdMap::<NodeId, ()>::new();
+        for _ in 0..256 {
+            nodes.insert(());
+        }
+    }
+}
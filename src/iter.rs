@@ -0,0 +1,113 @@
+use std::{
+    iter::{Enumerate, FusedIterator},
+    marker::PhantomData,
+    slice, vec,
+};
+
+use crate::CompactId;
+
+/// The ID owning slot `index` in its current generation, as recorded in
+/// `generations` (empty unless IDs are generational); slots are only filled
+/// for minted IDs.
+pub(crate) fn live_id<I: CompactId>(index: usize, generations: &[u32]) -> I {
+    let id = I::from_index(index).expect("occupied slots have an ID");
+    id.with_generation(generations.get(index).copied().unwrap_or(0))
+}
+
+/// Implements the iterator traits for a struct wrapping enumerated slots,
+/// skipping empty slots and converting each filled one with `$occupied`.
+macro_rules! slot_iterator {
+    ($name:ident<$($lt:lifetime,)? I, K>, $item:ty, |$slot:ident| $occupied:expr) => {
+        impl<$($lt,)? I: CompactId, K> Iterator for $name<$($lt,)? I, K> {
+            type Item = (I, $item);
+
+            fn next(&mut self) -> Option<Self::Item> {
+                let generations: &[u32] = &self.generations;
+                self.slots.find_map(|(index, $slot)| Some((live_id(index, generations), $occupied?)))
+            }
+
+            fn size_hint(&self) -> (usize, Option<usize>) {
+                (0, self.slots.size_hint().1)
+            }
+        }
+
+        impl<$($lt,)? I: CompactId, K> DoubleEndedIterator for $name<$($lt,)? I, K> {
+            fn next_back(&mut self) -> Option<Self::Item> {
+                let generations: &[u32] = &self.generations;
+                self.slots.by_ref().rev()
+                    .find_map(|(index, $slot)| Some((live_id(index, generations), $occupied?)))
+            }
+        }
+
+        impl<$($lt,)? I: CompactId, K> FusedIterator for $name<$($lt,)? I, K> {}
+    };
+}
+
+/// Iterator over `(id, &key)` pairs in ascending ID order.
+pub struct Iter<'a, I, K> {
+    slots: Enumerate<slice::Iter<'a, Option<K>>>,
+    generations: &'a [u32],
+    _ids: PhantomData<fn() -> I>,
+}
+
+impl<'a, I, K> Iter<'a, I, K> {
+    pub(crate) fn new(slots: &'a [Option<K>], generations: &'a [u32]) -> Self {
+        Self { slots: slots.iter().enumerate(), generations, _ids: PhantomData }
+    }
+}
+
+impl<I, K> Clone for Iter<'_, I, K> {
+    fn clone(&self) -> Self {
+        Self { slots: self.slots.clone(), generations: self.generations, _ids: PhantomData }
+    }
+}
+
+slot_iterator!(Iter<'a, I, K>, &'a K, |slot| slot.as_ref());
+
+/// Iterator over `(id, &mut value)` pairs in ascending ID order.
+pub struct IterMut<'a, I, K> {
+    slots: Enumerate<slice::IterMut<'a, Option<K>>>,
+    generations: &'a [u32],
+    _ids: PhantomData<fn() -> I>,
+}
+
+impl<'a, I, K> IterMut<'a, I, K> {
+    pub(crate) fn new(slots: &'a mut [Option<K>], generations: &'a [u32]) -> Self {
+        Self { slots: slots.iter_mut().enumerate(), generations, _ids: PhantomData }
+    }
+}
+
+slot_iterator!(IterMut<'a, I, K>, &'a mut K, |slot| slot.as_mut());
+
+/// Owning iterator over `(id, key)` pairs in ascending ID order.
+pub struct IntoIter<I, K> {
+    slots: Enumerate<vec::IntoIter<Option<K>>>,
+    generations: Vec<u32>,
+    _ids: PhantomData<fn() -> I>,
+}
+
+impl<I, K> IntoIter<I, K> {
+    pub(crate) fn new(slots: Vec<Option<K>>, generations: Vec<u32>) -> Self {
+        Self { slots: slots.into_iter().enumerate(), generations, _ids: PhantomData }
+    }
+}
+
+slot_iterator!(IntoIter<I, K>, K, |slot| slot);
+
+/// Draining iterator over `(id, key)` pairs in ascending ID order. The map is
+/// already empty when this is created; entries not consumed are dropped.
+pub struct Drain<'a, I, K> {
+    slots: Enumerate<vec::Drain<'a, Option<K>>>,
+    /// The generations the drained IDs were handed out in, as the map has
+    /// already moved their slots on.
+    generations: Vec<u32>,
+    _ids: PhantomData<fn() -> I>,
+}
+
+impl<'a, I, K> Drain<'a, I, K> {
+    pub(crate) fn new(slots: &'a mut Vec<Option<K>>, generations: Vec<u32>) -> Self {
+        Self { slots: slots.drain(..).enumerate(), generations, _ids: PhantomData }
+    }
+}
+
+slot_iterator!(Drain<'a, I, K>, K, |slot| slot);
@@ -0,0 +1,433 @@
+use std::{
+    borrow::Borrow,
+    fs::{self, File, OpenOptions},
+    hash::Hash,
+    io::{self, Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+};
+
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+
+use crate::{CompactId, CompactIdBiMap, Entry, ID};
+
+const SNAPSHOT: &str = "snapshot.json";
+const SNAPSHOT_TMP: &str = "snapshot.json.tmp";
+const LOG: &str = "journal.log";
+
+/// Length of the log header, the generation of the snapshot it applies to.
+const HEADER: usize = 8;
+/// Length of a record frame: payload length and CRC-32, both little endian.
+const FRAME: usize = 8;
+
+/// Flushes the entries of `dir`, so that a rename in it survives the
+/// machine crashing.
+#[cfg(unix)]
+fn sync_dir(dir: &Path) -> io::Result<()> {
+    File::open(dir)?.sync_all()
+}
+
+#[cfg(not(unix))]
+fn sync_dir(_dir: &Path) -> io::Result<()> {
+    Ok(())
+}
+
+/// One change appended to the log.
+#[derive(Serialize, Deserialize)]
+enum Record<K, I> {
+    Insert { id: I, key: K },
+    Remove { id: I },
+}
+
+#[derive(Serialize, Deserialize)]
+struct Snapshot<M> {
+    /// Bumped on every snapshot; the log only applies to its own generation.
+    generation: u64,
+    map: M,
+}
+
+/// CRC-32 (IEEE) of `bytes`.
+fn crc32(bytes: &[u8]) -> u32 {
+    !bytes.iter().fold(!0u32, |crc, &byte| {
+        (0..8).fold(crc ^ u32::from(byte), |crc, _| (crc >> 1) ^ (0xEDB8_8320 & (crc & 1).wrapping_neg()))
+    })
+}
+
+fn invalid_data(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+/// The payload of the record at `at` and the offset past it, or `None` if
+/// the log ends there or the record is torn.
+fn read_record(log: &[u8], at: usize) -> Option<(&[u8], usize)> {
+    let frame = log.get(at..at + FRAME)?;
+    let len = u32::from_le_bytes(frame[..4].try_into().unwrap()) as usize;
+    let crc = u32::from_le_bytes(frame[4..].try_into().unwrap());
+    let payload = log.get(at + FRAME..(at + FRAME).checked_add(len)?)?;
+    (crc32(payload) == crc).then_some((payload, at + FRAME + len))
+}
+
+/// Applies a replayed record, checking that inserts get the same ID as when
+/// they were logged.
+fn replay<K, I>(map: &mut CompactIdBiMap<K, I>, record: Record<K, I>) -> io::Result<()>
+where
+    K: Hash + Eq,
+    I: CompactId + Eq
+{
+    match record {
+        Record::Insert { id, key } => match map.entry(key) {
+            Entry::Occupied(_) => Err(invalid_data("journal inserts a key twice")),
+            Entry::Vacant(entry) => {
+                if entry.id().map_err(io::Error::other)? != id {
+                    return Err(invalid_data("journal replays to a different ID"));
+                }
+                entry.try_insert().map(|_| ()).map_err(io::Error::other)
+            }
+        },
+        Record::Remove { id } => match map.remove_id(id) {
+            Some(_) => Ok(()),
+            None => Err(invalid_data("journal removes a free ID")),
+        },
+    }
+}
+
+/// A `CompactIdBiMap` kept on disk in a directory holding a snapshot and an
+/// append-only log of the changes made since.
+///
+/// Every insert and removal appends a checksummed record to the log before
+/// returning; the log is replayed on `open`, through the same allocator, so
+/// every ID comes back exactly as it was handed out. A record torn by a crash
+/// is discarded, along with anything after it. Every `snapshot_interval`
+/// records the whole map is written to a new snapshot and the log emptied.
+///
+/// Records are written straight to the file, so they survive the process
+/// crashing; call `sync` to also make them survive the machine crashing.
+/// After an I/O error the map refuses further changes, since memory may be
+/// ahead of the disk: reopen it to continue from what was written.
+///
+/// The change that triggers an automatic snapshot is already in the log, so
+/// it succeeds even if the snapshot fails; see `snapshot_error`.
+pub struct JournaledIdBiMap<K, I = ID>
+    where K: Eq + Hash
+{
+    map: CompactIdBiMap<K, I>,
+    dir: PathBuf,
+    log: File,
+    generation: u64,
+    /// Records appended since the last snapshot.
+    records: usize,
+    snapshot_interval: usize,
+    /// Why the last automatic snapshot failed, if it did.
+    snapshot_error: Option<io::Error>,
+    failed: bool,
+}
+
+impl<K, I> JournaledIdBiMap<K, I> where
+K: Hash + Eq + Serialize + DeserializeOwned,
+I: CompactId + Eq + Serialize + DeserializeOwned
+{
+    /// Opens the map stored in `dir`, creating an empty one if needed.
+    pub fn open(dir: impl AsRef<Path>) -> io::Result<Self> {
+        let dir = dir.as_ref().to_path_buf();
+        fs::create_dir_all(&dir)?;
+        let (generation, mut map) = match fs::read(dir.join(SNAPSHOT)) {
+            Ok(bytes) => {
+                let snapshot: Snapshot<CompactIdBiMap<K, I>> = serde_json::from_slice(&bytes)?;
+                (snapshot.generation, snapshot.map)
+            }
+            Err(err) if err.kind() == io::ErrorKind::NotFound => (0, CompactIdBiMap::new()),
+            Err(err) => return Err(err),
+        };
+
+        let mut log = OpenOptions::new().read(true).write(true).create(true).truncate(false).open(dir.join(LOG))?;
+        let mut bytes = Vec::new();
+        log.read_to_end(&mut bytes)?;
+        let header = generation.to_le_bytes();
+        let mut records = 0;
+        let end = if bytes.get(..HEADER) == Some(&header[..]) {
+            let mut at = HEADER;
+            while let Some((payload, next)) = read_record(&bytes, at) {
+                replay(&mut map, serde_json::from_slice(payload)?)?;
+                records += 1;
+                at = next;
+            }
+            at
+        } else {
+            // A new log, a torn header, or a log from before the snapshot,
+            // whose records the snapshot already holds.
+            log.set_len(0)?;
+            log.rewind()?;
+            log.write_all(&header)?;
+            HEADER
+        };
+        log.set_len(end as u64)?;
+        log.seek(SeekFrom::Start(end as u64))?;
+
+        Ok(Self {
+            map,
+            dir,
+            log,
+            generation,
+            records,
+            snapshot_interval: 1024,
+            snapshot_error: None,
+            failed: false,
+        })
+    }
+
+    /// Sets how many records the log may hold before a snapshot replaces
+    /// them; 1024 by default.
+    pub fn set_snapshot_interval(&mut self, records: usize) {
+        self.snapshot_interval = records.max(1);
+    }
+
+    /// The in-memory map, for reads beyond `get` and `get_key`.
+    pub fn as_map(&self) -> &CompactIdBiMap<K, I> {
+        &self.map
+    }
+
+    pub fn get<Q>(&self, key: &Q) -> Option<I>
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Hash + Eq
+    {
+        self.map.get(key)
+    }
+
+    pub fn get_key(&self, id: I) -> Option<&K> {
+        self.map.get_key(id)
+    }
+
+    /// The number of keys in the map.
+    pub fn len(&self) -> usize {
+        self.map.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.map.is_empty()
+    }
+
+    /// Returns the ID of `k`, inserting and logging it first if needed.
+    pub fn get_or_insert(&mut self, k: K) -> io::Result<I> {
+        self.check()?;
+        let id = match self.map.entry(k) {
+            Entry::Occupied(entry) => return Ok(entry.id()),
+            Entry::Vacant(entry) => entry.try_insert().map_err(io::Error::other)?,
+        };
+        let key = self.map.get_key(id).expect("just inserted");
+        let record = serde_json::to_vec(&Record::Insert { id, key });
+        self.failed |= record.is_err();
+        self.append(&record?)?;
+        Ok(id)
+    }
+
+    /// Removes `k`, logging the removal, and returns the ID it had.
+    pub fn remove<Q>(&mut self, k: &Q) -> io::Result<Option<I>>
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Hash + Eq
+    {
+        match self.map.get(k) {
+            Some(id) => self.remove_id(id).map(|_| Some(id)),
+            None => Ok(None),
+        }
+    }
+
+    /// Removes the key of `id`, logging the removal, and returns it.
+    pub fn remove_id(&mut self, id: I) -> io::Result<Option<K>> {
+        self.check()?;
+        let Some(k) = self.map.remove_id(id) else { return Ok(None) };
+        let record = serde_json::to_vec(&Record::<K, I>::Remove { id });
+        self.failed |= record.is_err();
+        self.append(&record?)?;
+        Ok(Some(k))
+    }
+
+    /// Flushes the log to the disk.
+    pub fn sync(&mut self) -> io::Result<()> {
+        let result = self.log.sync_data();
+        self.failed |= result.is_err();
+        result
+    }
+
+    /// Writes the whole map to a new snapshot and empties the log.
+    pub fn snapshot(&mut self) -> io::Result<()> {
+        self.check()?;
+        let tmp = self.prepare_snapshot()?;
+        let result = self.install_snapshot(&tmp);
+        match &result {
+            Ok(()) => self.snapshot_error = None,
+            Err(_) => self.failed = true,
+        }
+        result
+    }
+
+    /// Why the last automatic snapshot failed, or `None` if it succeeded.
+    ///
+    /// A failure while writing the new snapshot leaves the old one and the
+    /// log intact, and the snapshot is retried on the next change. A failure
+    /// after the new snapshot replaced the old one leaves the map failed,
+    /// like any other I/O error.
+    pub fn snapshot_error(&self) -> Option<&io::Error> {
+        self.snapshot_error.as_ref()
+    }
+
+    fn check(&self) -> io::Result<()> {
+        if self.failed {
+            return Err(io::Error::other("journal failed earlier; reopen it"));
+        }
+        Ok(())
+    }
+
+    /// Appends a framed record, taking a snapshot when the log is full.
+    fn append(&mut self, payload: &[u8]) -> io::Result<()> {
+        let len = u32::try_from(payload.len()).map_err(|_| invalid_data("record too large"))?;
+        let mut frame = Vec::with_capacity(FRAME + payload.len());
+        frame.extend_from_slice(&len.to_le_bytes());
+        frame.extend_from_slice(&crc32(payload).to_le_bytes());
+        frame.extend_from_slice(payload);
+        let result = self.log.write_all(&frame);
+        self.failed |= result.is_err();
+        result?;
+        self.records += 1;
+        if self.records >= self.snapshot_interval {
+            // The record is logged, so the change stands whatever happens.
+            self.snapshot_error = self.snapshot().err();
+        }
+        Ok(())
+    }
+
+    /// Writes the next snapshot to a temporary file, returning its path.
+    /// Failing here leaves the snapshot and the log as they were.
+    fn prepare_snapshot(&self) -> io::Result<PathBuf> {
+        let tmp = self.dir.join(SNAPSHOT_TMP);
+        let mut file = File::create(&tmp)?;
+        let snapshot = Snapshot { generation: self.generation + 1, map: &self.map };
+        serde_json::to_writer(&mut file, &snapshot)?;
+        file.sync_all()?;
+        Ok(tmp)
+    }
+
+    /// Replaces the snapshot atomically with the prepared one, then starts a
+    /// log for its generation. A crash in between leaves a stale log, which
+    /// `open` recognizes by its generation and discards.
+    fn install_snapshot(&mut self, tmp: &Path) -> io::Result<()> {
+        let generation = self.generation + 1;
+        fs::rename(tmp, self.dir.join(SNAPSHOT))?;
+        self.generation = generation;
+        sync_dir(&self.dir)?;
+
+        self.log.set_len(0)?;
+        self.log.rewind()?;
+        self.log.write_all(&generation.to_le_bytes())?;
+        self.log.sync_data()?;
+        self.records = 0;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test_journal {
+    use super::*;
+
+    /// A fresh directory for one test.
+    fn scratch(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("compact-id-map-{}-{name}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    #[test]
+    fn test_replay() {
+        let dir = scratch("replay");
+        let mut plain = CompactIdBiMap::<String, u32>::new();
+        {
+            let mut journal = JournaledIdBiMap::<String, u32>::open(&dir).unwrap();
+            for word in ["a", "b", "c", "d"] {
+                assert_eq!(plain.get_or_insert(word.into()), journal.get_or_insert(word.into()).unwrap());
+            }
+            plain.remove("b");
+            plain.remove("d");
+            assert_eq!(Some(1), journal.remove("b").unwrap());
+            assert_eq!(Some(String::from("d")), journal.remove_id(3).unwrap());
+        }
+
+        let mut journal = JournaledIdBiMap::<String, u32>::open(&dir).unwrap();
+        assert_eq!(2, journal.len());
+        assert_eq!(Some(2), journal.get("c"));
+        for word in ["e", "f", "g"] {
+            assert_eq!(plain.get_or_insert(word.into()), journal.get_or_insert(word.into()).unwrap());
+        }
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_torn_record() {
+        let dir = scratch("torn");
+        {
+            let mut journal = JournaledIdBiMap::<String>::open(&dir).unwrap();
+            journal.get_or_insert(String::from("kept")).unwrap();
+            journal.get_or_insert(String::from("torn")).unwrap();
+        }
+        let log = dir.join(LOG);
+        let len = fs::metadata(&log).unwrap().len();
+        OpenOptions::new().write(true).open(&log).unwrap().set_len(len - 3).unwrap();
+
+        let mut journal = JournaledIdBiMap::<String>::open(&dir).unwrap();
+        assert_eq!(Some(0), journal.get("kept"));
+        assert_eq!(None, journal.get("torn"));
+        assert_eq!(1, journal.get_or_insert(String::from("next")).unwrap());
+        drop(journal);
+        assert_eq!(Some(1), JournaledIdBiMap::<String>::open(&dir).unwrap().get("next"));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_snapshots() {
+        let dir = scratch("snapshots");
+        let stale;
+        {
+            let mut journal = JournaledIdBiMap::<u64, u16>::open(&dir).unwrap();
+            journal.set_snapshot_interval(5);
+            for k in 0..10 {
+                journal.get_or_insert(k).unwrap();
+            }
+            journal.remove(&4).unwrap();
+            journal.remove(&7).unwrap();
+            stale = fs::read(dir.join(LOG)).unwrap();
+            assert!(stale.len() > HEADER);
+            journal.snapshot().unwrap();
+            assert_eq!(HEADER as u64, fs::metadata(dir.join(LOG)).unwrap().len());
+        }
+        // As if the process died after the snapshot but before the log was
+        // emptied.
+        fs::write(dir.join(LOG), stale).unwrap();
+
+        let mut journal = JournaledIdBiMap::<u64, u16>::open(&dir).unwrap();
+        assert_eq!(8, journal.len());
+        assert_eq!(None, journal.get(&4));
+        assert_eq!(7, journal.get_or_insert(100).unwrap());
+        assert_eq!(4, journal.get_or_insert(101).unwrap());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_failed_snapshot() {
+        let dir = scratch("failed-snapshot");
+        let mut journal = JournaledIdBiMap::<u64, u16>::open(&dir).unwrap();
+        journal.set_snapshot_interval(2);
+        // A directory in the way of the temporary snapshot.
+        fs::create_dir(dir.join(SNAPSHOT_TMP)).unwrap();
+        assert_eq!(0, journal.get_or_insert(0).unwrap());
+        assert_eq!(1, journal.get_or_insert(1).unwrap());
+        assert!(journal.snapshot_error().is_some());
+        assert_eq!(2, journal.get_or_insert(2).unwrap());
+
+        fs::remove_dir(dir.join(SNAPSHOT_TMP)).unwrap();
+        assert_eq!(3, journal.get_or_insert(3).unwrap());
+        assert!(journal.snapshot_error().is_none());
+        assert_eq!(HEADER as u64, fs::metadata(dir.join(LOG)).unwrap().len());
+        drop(journal);
+        assert_eq!(4, JournaledIdBiMap::<u64, u16>::open(&dir).unwrap().len());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,119 @@
+use std::hash::{BuildHasher, Hash};
+
+use hashbrown::{HashMap, HashSet};
+use serde::{de::Error as _, Deserialize, Deserializer};
+
+use crate::{compact_id_map::CompactIdMapRepr, CompactId, CompactIdBiMap, CompactIdMap, Error, RecyclePolicy};
+
+/// A map in the format written by version 0.1, for loading data saved
+/// before the slots became a vector. Maps load with `RecyclePolicy::Lifo`,
+/// the only order 0.1 had, so they keep handing out the same IDs. There is
+/// no `Serialize`: saving the map again writes the current format.
+///
+/// ```
+/// use compact_id_map::{CompactIdBiMap, LegacyFormat};
+///
+/// let old = r#"{"ids":{"a":0,"c":2},"keys":{"0":"a","2":"c"},"recycle_bin":[1],"next_new_id":3}"#;
+/// let LegacyFormat(mut ids) = serde_json::from_str::<LegacyFormat<CompactIdBiMap<String>>>(old).unwrap();
+/// assert_eq!(Some(2), ids.get("c"));
+/// assert_eq!(1, ids.insert(String::from("b")));
+/// ```
+#[derive(Debug)]
+pub struct LegacyFormat<M>(pub M);
+
+/// The fields of a 0.1 map. A bimap also wrote `ids`, its reverse index,
+/// which is rebuilt from `keys` instead.
+#[derive(Deserialize)]
+#[serde(bound(deserialize = "I: Eq + Hash + Deserialize<'de>, K: Deserialize<'de>"))]
+struct LegacyRepr<I, K> {
+    keys: HashMap<I, K>,
+    recycle_bin: Vec<I>,
+    next_new_id: I,
+}
+
+impl<I, K> LegacyRepr<I, K> where
+I: CompactId + Eq + Hash
+{
+    /// Lays the keys out in slots and validates the result like any other
+    /// loaded map.
+    fn into_map(self) -> Result<CompactIdMap<I, K>, Error> {
+        let Self { keys, recycle_bin, next_new_id } = self;
+        let end = next_new_id.to_index().ok_or(Error::InvalidId)?;
+        // Every ID below `next_new_id` was either live or recycled, so a
+        // larger one leaks IDs. Report that before allocating `end` slots
+        // for what may be a tiny input.
+        if end > keys.len() + recycle_bin.len() {
+            let used = keys.keys().chain(&recycle_bin).map(|id| id.to_index()).collect::<HashSet<_>>();
+            let index = (0..end).find(|&index| !used.contains(&Some(index))).expect("fewer IDs than slots");
+            return Err(Error::LeakedId { index });
+        }
+        let mut slots = Vec::new();
+        slots.resize_with(end, || None);
+        for (id, k) in keys {
+            let index = id.to_index().ok_or(Error::InvalidId)?;
+            *slots.get_mut(index).ok_or(Error::IdOutOfRange { index })? = Some(k);
+        }
+        CompactIdMap::try_from(CompactIdMapRepr {
+            keys: slots,
+            recycle_bin: Some(recycle_bin.into()),
+            next_new_id: Some(next_new_id),
+            policy: RecyclePolicy::Lifo,
+            max_id: None,
+            generations: Vec::new(),
+        })
+    }
+}
+
+impl<'de, I, K> Deserialize<'de> for LegacyFormat<CompactIdMap<I, K>> where
+I: CompactId + Eq + Hash + Deserialize<'de>,
+K: Deserialize<'de>
+{
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let repr = LegacyRepr::deserialize(deserializer)?;
+        repr.into_map().map(LegacyFormat).map_err(D::Error::custom)
+    }
+}
+
+impl<'de, K, I, S> Deserialize<'de> for LegacyFormat<CompactIdBiMap<K, I, S>> where
+K: Hash + Eq + Deserialize<'de>,
+I: CompactId + Eq + Hash + Deserialize<'de>,
+S: BuildHasher + Default
+{
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let LegacyFormat(map) = LegacyFormat::<CompactIdMap<I, K>>::deserialize(deserializer)?;
+        CompactIdBiMap::try_from(map).map(LegacyFormat).map_err(D::Error::custom)
+    }
+}
+
+#[cfg(test)]
+mod test_legacy {
+    use super::*;
+
+    fn load(json: &str) -> Result<CompactIdMap<u32, char>, String> {
+        serde_json::from_str::<LegacyFormat<_>>(json).map(|legacy| legacy.0).map_err(|err| err.to_string())
+    }
+
+    #[test]
+    fn test_legacy_map() {
+        let mut map = load(r#"{"keys":{"0":"a","3":"d"},"recycle_bin":[2,1],"next_new_id":4}"#).unwrap();
+        assert_eq!(Some(&'d'), map.get(3));
+        assert_eq!(RecyclePolicy::Lifo, map.policy());
+        assert_eq!(1, map.insert('b'));
+        assert_eq!(2, map.insert('c'));
+        assert_eq!(4, map.insert('e'));
+        assert_eq!(r#"{"keys":["a","b","c","d","e"],"policy":"Lifo"}"#, serde_json::to_string(&map).unwrap());
+    }
+
+    #[test]
+    fn test_invalid_legacy_maps() {
+        let err = load(r#"{"keys":{},"recycle_bin":[],"next_new_id":4000000000}"#).unwrap_err();
+        assert!(err.contains(&Error::LeakedId { index: 0 }.to_string()));
+        let err = load(r#"{"keys":{"5":"f"},"recycle_bin":[0],"next_new_id":2}"#).unwrap_err();
+        assert!(err.contains(&Error::IdOutOfRange { index: 5 }.to_string()));
+        let err = load(r#"{"keys":{"0":"a"},"recycle_bin":[0],"next_new_id":2}"#).unwrap_err();
+        assert!(err.contains(&Error::RecycledIdInUse { index: 0 }.to_string()));
+
+        let duplicate = r#"{"ids":{"a":0},"keys":{"0":"a","1":"a"},"recycle_bin":[],"next_new_id":2}"#;
+        assert!(serde_json::from_str::<LegacyFormat<CompactIdBiMap<String, u32>>>(duplicate).is_err());
+    }
+}
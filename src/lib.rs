@@ -1,2 +1,41 @@
+mod checkpoint;
 mod compact_id_map;
-pub use compact_id_map::*;
\ No newline at end of file
+mod concurrent;
+mod entry;
+mod error;
+mod generational;
+mod guard;
+mod id;
+mod iter;
+#[cfg(feature = "journal")]
+mod journal;
+mod legacy;
+mod options;
+mod persistent;
+mod policy;
+mod ref_counted;
+mod remap;
+mod secondary;
+mod value_map;
+pub use checkpoint::Checkpoint;
+pub use compact_id_map::*;
+pub use concurrent::*;
+pub use entry::*;
+pub use error::*;
+pub use generational::*;
+pub use guard::*;
+pub use id::*;
+pub use iter::*;
+#[cfg(feature = "journal")]
+pub use journal::*;
+pub use legacy::*;
+pub use options::*;
+pub use persistent::*;
+pub use policy::*;
+pub use ref_counted::*;
+pub use remap::*;
+pub use secondary::*;
+pub use value_map::*;
+
+#[doc(hidden)]
+pub use serde as __serde;
\ No newline at end of file
@@ -0,0 +1,52 @@
+use crate::RecyclePolicy;
+
+/// Settings for a new map, for combining what the `with_capacity`,
+/// `with_policy` and `with_max_id` constructors each set on their own.
+///
+/// ```
+/// use compact_id_map::{CompactIdMap, IdMapOptions, RecyclePolicy};
+///
+/// let options = IdMapOptions::new().policy(RecyclePolicy::LowestFirst).max_id(999).capacity(1000);
+/// let map = CompactIdMap::<u32, String>::with_options(options);
+/// assert_eq!((RecyclePolicy::LowestFirst, Some(999)), (map.policy(), map.max_id()));
+/// assert!(map.capacity() >= 1000);
+/// ```
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct IdMapOptions<I> {
+    pub(crate) capacity: usize,
+    pub(crate) policy: RecyclePolicy,
+    pub(crate) max_id: Option<I>,
+}
+
+impl<I> Default for IdMapOptions<I> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<I> IdMapOptions<I> {
+    /// The settings of `new`: no reserved room, the default policy and no
+    /// maximum ID.
+    pub fn new() -> Self {
+        Self {
+            capacity: 0,
+            policy: RecyclePolicy::default(),
+            max_id: None,
+        }
+    }
+
+    /// Room for `capacity` entries before reallocating.
+    pub fn capacity(self, capacity: usize) -> Self {
+        Self { capacity, ..self }
+    }
+
+    pub fn policy(self, policy: RecyclePolicy) -> Self {
+        Self { policy, ..self }
+    }
+
+    /// Never hand out an ID above `max_id`, while still reusing freed IDs
+    /// below it.
+    pub fn max_id(self, max_id: I) -> Self {
+        Self { max_id: Some(max_id), ..self }
+    }
+}
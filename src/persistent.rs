@@ -0,0 +1,379 @@
+use std::{
+    borrow::Borrow,
+    hash::{BuildHasher, Hash},
+    sync::Arc,
+};
+
+use hashbrown::DefaultHashBuilder;
+
+use crate::{CompactId, Error, ID};
+
+/// Bits of the key consumed per level of a `Hamt`.
+const BITS: u32 = 5;
+const MASK: u64 = (1 << BITS) - 1;
+
+#[derive(Clone)]
+enum Slot<V> {
+    Leaf(u64, V),
+    Branch(Arc<Node<V>>),
+}
+
+/// A trie node holding one slot per set bit of `bitmap`.
+#[derive(Clone)]
+struct Node<V> {
+    bitmap: u32,
+    slots: Vec<Slot<V>>,
+}
+
+impl<V: Clone> Node<V> {
+    fn empty() -> Self {
+        Self { bitmap: 0, slots: Vec::new() }
+    }
+
+    /// The bit for `key` at `shift` and the position of its slot, if present.
+    fn locate(&self, key: u64, shift: u32) -> (u32, usize) {
+        let bit = 1 << ((key >> shift) & MASK);
+        (bit, (self.bitmap & (bit - 1)).count_ones() as usize)
+    }
+
+    fn get(&self, key: u64, shift: u32) -> Option<&V> {
+        let (bit, at) = self.locate(key, shift);
+        if self.bitmap & bit == 0 {
+            return None;
+        }
+        match &self.slots[at] {
+            Slot::Leaf(other, v) => (*other == key).then_some(v),
+            Slot::Branch(node) => node.get(key, shift + BITS),
+        }
+    }
+
+    /// A copy of this node with `key` set to `v`, sharing untouched children.
+    fn insert(&self, key: u64, v: V, shift: u32) -> Self {
+        let (bit, at) = self.locate(key, shift);
+        let mut node = self.clone();
+        if self.bitmap & bit == 0 {
+            node.bitmap |= bit;
+            node.slots.insert(at, Slot::Leaf(key, v));
+            return node;
+        }
+        node.slots[at] = match &self.slots[at] {
+            Slot::Leaf(other, _) if *other == key => Slot::Leaf(key, v),
+            Slot::Leaf(other, old) => {
+                let branch = Node::empty()
+                    .insert(*other, old.clone(), shift + BITS)
+                    .insert(key, v, shift + BITS);
+                Slot::Branch(Arc::new(branch))
+            }
+            Slot::Branch(child) => Slot::Branch(Arc::new(child.insert(key, v, shift + BITS))),
+        };
+        node
+    }
+
+    /// A copy of this node without `key`, or `None` if it is absent. Branches
+    /// left with a single leaf are folded into their parent.
+    fn remove(&self, key: u64, shift: u32) -> Option<Self> {
+        let (bit, at) = self.locate(key, shift);
+        if self.bitmap & bit == 0 {
+            return None;
+        }
+        let mut node = self.clone();
+        match &self.slots[at] {
+            Slot::Leaf(other, _) if *other == key => {
+                node.bitmap &= !bit;
+                node.slots.remove(at);
+            }
+            Slot::Leaf(..) => return None,
+            Slot::Branch(child) => {
+                let child = child.remove(key, shift + BITS)?;
+                node.slots[at] = match child.slots.as_slice() {
+                    [leaf @ Slot::Leaf(..)] => leaf.clone(),
+                    _ => Slot::Branch(Arc::new(child)),
+                };
+            }
+        }
+        Some(node)
+    }
+}
+
+/// A persistent hash array mapped trie from `u64` keys to values. Updates
+/// copy the path to the changed leaf and share everything else.
+#[derive(Clone)]
+struct Hamt<V> {
+    root: Arc<Node<V>>,
+}
+
+impl<V: Clone> Hamt<V> {
+    fn new() -> Self {
+        Self { root: Arc::new(Node::empty()) }
+    }
+
+    fn get(&self, key: u64) -> Option<&V> {
+        self.root.get(key, 0)
+    }
+
+    fn insert(&self, key: u64, v: V) -> Self {
+        Self { root: Arc::new(self.root.insert(key, v, 0)) }
+    }
+
+    fn remove(&self, key: u64) -> Option<Self> {
+        Some(Self { root: Arc::new(self.root.remove(key, 0)?) })
+    }
+}
+
+/// A persistent stack, used as a LIFO recycle bin.
+struct Stack<T>(Option<Arc<(T, Stack<T>)>>);
+
+impl<T> Clone for Stack<T> {
+    fn clone(&self) -> Self {
+        Self(self.0.clone())
+    }
+}
+
+impl<T: Copy> Stack<T> {
+    fn push(&self, t: T) -> Self {
+        Self(Some(Arc::new((t, self.clone()))))
+    }
+
+    fn pop(&self) -> Option<(T, Self)> {
+        let (t, rest) = &**self.0.as_ref()?;
+        Some((*t, rest.clone()))
+    }
+}
+
+impl<T> Drop for Stack<T> {
+    /// Unlinks uniquely owned cells one by one, so that dropping a long bin
+    /// does not recurse once per cell.
+    fn drop(&mut self) {
+        let mut next = self.0.take();
+        while let Some(cell) = next {
+            next = match Arc::try_unwrap(cell) {
+                Ok((_, mut rest)) => rest.0.take(),
+                Err(_) => None,
+            };
+        }
+    }
+}
+
+/// The keys whose hash is the trie key; almost always just one.
+type Bucket<K, I> = Arc<[(Arc<K>, I)]>;
+
+/// A persistent `CompactIdBiMap`: `insert` and the removals return a new map
+/// and leave the old one untouched, sharing all but a few trie nodes with it,
+/// so cloning is O(1) and old versions stay readable.
+///
+/// Freed IDs are reused most recently freed first, as with the default
+/// `RecyclePolicy::Lifo`. Keys are shared between versions, hence
+/// `remove_id` hands back an `Arc`.
+pub struct PersistentCompactIdBiMap<K, I = ID> {
+    keys: Hamt<Arc<K>>,
+    /// Reverse index keyed by hash.
+    ids: Hamt<Bucket<K, I>>,
+    recycle_bin: Stack<I>,
+    next_new_id: I,
+    len: usize,
+    hash_builder: DefaultHashBuilder,
+}
+
+impl<K, I: Copy> Clone for PersistentCompactIdBiMap<K, I> {
+    fn clone(&self) -> Self {
+        Self {
+            keys: self.keys.clone(),
+            ids: self.ids.clone(),
+            recycle_bin: self.recycle_bin.clone(),
+            next_new_id: self.next_new_id,
+            len: self.len,
+            hash_builder: self.hash_builder,
+        }
+    }
+}
+
+impl<K, I> Default for PersistentCompactIdBiMap<K, I> where
+K: Hash + Eq,
+I: CompactId + Eq
+{
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// The trie key of slot `id`.
+fn slot_key<I: CompactId>(id: I) -> Option<u64> {
+    id.to_index().map(|index| index as u64)
+}
+
+impl<K, I> PersistentCompactIdBiMap<K, I> where
+K: Hash + Eq,
+I: CompactId + Eq
+{
+    pub fn new() -> Self {
+        Self {
+            keys: Hamt::new(),
+            ids: Hamt::new(),
+            recycle_bin: Stack(None),
+            next_new_id: I::from_index(0).expect("ID type cannot represent the first ID"),
+            len: 0,
+            hash_builder: DefaultHashBuilder::default(),
+        }
+    }
+
+    pub fn get<Q>(&self, key: &Q) -> Option<I>
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Hash + Eq
+    {
+        let bucket = self.ids.get(self.hash_builder.hash_one(key))?;
+        bucket.iter().find(|(k, _)| (**k).borrow() == key).map(|&(_, id)| id)
+    }
+
+    pub fn get_key(&self, id: I) -> Option<&K> {
+        self.keys.get(slot_key(id)?).map(|k| &**k)
+    }
+
+    pub fn contains_id(&self, id: I) -> bool {
+        self.get_key(id).is_some()
+    }
+
+    /// The number of keys in the map.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Returns the map with `k` interned and the ID of `k`; the map is only
+    /// copied if `k` is new.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `k` is new and no ID is left.
+    pub fn get_or_insert(&self, k: K) -> (Self, I) {
+        match self.get(&k) {
+            Some(id) => (self.clone(), id),
+            None => self.insert(k),
+        }
+    }
+
+    /// Returns the map with `k`, a key not in this map yet, interned under a
+    /// new ID.
+    ///
+    /// # Panics
+    ///
+    /// Panics if no ID is left; see `try_insert`.
+    pub fn insert(&self, k: K) -> (Self, I) {
+        self.try_insert(k).unwrap_or_else(|err| panic!("{err}"))
+    }
+
+    /// Like `insert`, but returns `Error::IdSpaceExhausted` instead of
+    /// panicking if no ID is left.
+    pub fn try_insert(&self, k: K) -> Result<(Self, I), Error> {
+        debug_assert!(self.get(&k).is_none());
+        let mut map = self.clone();
+        let id = match self.recycle_bin.pop() {
+            Some((id, rest)) => {
+                map.recycle_bin = rest;
+                id
+            }
+            None => {
+                map.next_new_id = self.next_new_id.to_index()
+                    .and_then(|index| index.checked_add(1))
+                    .and_then(I::from_index)
+                    .ok_or(Error::IdSpaceExhausted)?;
+                self.next_new_id
+            }
+        };
+        let hash = self.hash_builder.hash_one(&k);
+        let k = Arc::new(k);
+        let bucket = match self.ids.get(hash) {
+            Some(bucket) => bucket.iter().cloned().chain([(Arc::clone(&k), id)]).collect(),
+            None => Arc::from([(Arc::clone(&k), id)]),
+        };
+        map.ids = map.ids.insert(hash, bucket);
+        map.keys = map.keys.insert(slot_key(id).expect("fresh IDs have a slot"), k);
+        map.len += 1;
+        Ok((map, id))
+    }
+
+    /// Returns the map without `k` and the ID `k` had, or `None` if `k` is
+    /// not in this map.
+    pub fn remove<Q>(&self, k: &Q) -> Option<(Self, I)>
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Hash + Eq
+    {
+        let id = self.get(k)?;
+        let (map, _) = self.remove_id(id)?;
+        Some((map, id))
+    }
+
+    /// Returns the map without the key of `id` and that key, or `None` if
+    /// `id` is not in this map.
+    pub fn remove_id(&self, id: I) -> Option<(Self, Arc<K>)> {
+        let slot = slot_key(id)?;
+        let k = Arc::clone(self.keys.get(slot)?);
+        let hash = self.hash_builder.hash_one(&*k);
+        let bucket = self.ids.get(hash).expect("live keys are indexed");
+        let rest: Bucket<K, I> = bucket.iter().filter(|&&(_, other)| other != id).cloned().collect();
+        let mut map = self.clone();
+        map.ids = if rest.is_empty() {
+            map.ids.remove(hash).expect("live keys are indexed")
+        } else {
+            map.ids.insert(hash, rest)
+        };
+        map.keys = map.keys.remove(slot).expect("checked above");
+        map.recycle_bin = map.recycle_bin.push(id);
+        map.len -= 1;
+        Some((map, k))
+    }
+}
+
+#[cfg(test)]
+mod test_persistent {
+    use super::*;
+
+    #[test]
+    fn test_versions() {
+        let empty = PersistentCompactIdBiMap::<String, u32>::new();
+        let (v1, a) = empty.insert(String::from("a"));
+        let (v2, b) = v1.insert(String::from("b"));
+        let (v3, removed) = v2.remove("a").unwrap();
+        assert_eq!(a, removed);
+        let (v4, c) = v3.insert(String::from("c"));
+        assert_eq!(a, c);
+
+        assert!(empty.is_empty());
+        assert_eq!((Some(a), None), (v1.get("a"), v1.get("b")));
+        assert_eq!((Some(a), Some(b)), (v2.get("a"), v2.get("b")));
+        assert_eq!((None, 1), (v3.get("a"), v3.len()));
+        assert_eq!(Some(&String::from("a")), v2.get_key(a));
+        assert_eq!(Some(&String::from("c")), v4.get_key(c));
+        assert_eq!(b, v4.get_or_insert(String::from("b")).1);
+
+        let (v5, key) = v4.remove_id(b).unwrap();
+        assert_eq!("b", *key);
+        assert_eq!(None, v5.remove_id(b).map(|(_, k)| k));
+        assert!(v4.contains_id(b));
+    }
+
+    #[test]
+    fn test_many_versions() {
+        let mut versions = vec![PersistentCompactIdBiMap::<u32, u16>::new()];
+        for k in 0..2000 {
+            let (next, id) = versions.last().unwrap().insert(k);
+            assert_eq!(k, u32::from(id));
+            versions.push(next);
+        }
+        let mut map = versions[2000].clone();
+        for k in (0..2000).step_by(3) {
+            map = map.remove(&k).unwrap().0;
+        }
+        for k in 0..2000 {
+            assert_eq!(Some(k as u16), versions[2000].get(&k));
+            assert_eq!(k % 3 != 0, map.get(&k).is_some());
+            assert_eq!(Some(&k), versions[k as usize + 1].get_key(k as u16));
+            assert_eq!(None, versions[k as usize].get(&k));
+        }
+        assert_eq!(1998, map.insert(5000).1);
+    }
+}
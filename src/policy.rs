@@ -0,0 +1,161 @@
+use std::collections::{BTreeMap, VecDeque};
+
+use serde::{Deserialize, Serialize, Serializer};
+
+use crate::{CompactId, Error};
+
+/// Which freed ID a map hands out next.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum RecyclePolicy {
+    /// Reuse the most recently freed ID first.
+    #[default]
+    Lifo,
+    /// Reuse the lowest freed ID first, keeping the ID space tight.
+    LowestFirst,
+    /// Reuse the least recently freed ID first, maximizing the time before
+    /// an ID comes back.
+    Fifo,
+    /// Never reuse IDs; every insert gets a new, higher ID.
+    Never,
+}
+
+/// The freed IDs waiting to be reused. `LowestFirst` keeps them sorted by
+/// index in a B-tree, so that freeing an ID under heavy churn costs
+/// O(log n) rather than shifting the rest of the bin; the other policies
+/// queue them in the order they were freed.
+#[derive(Clone, Debug)]
+pub(crate) enum RecycleBin<I> {
+    Queue(VecDeque<I>),
+    Sorted(BTreeMap<usize, I>),
+}
+
+impl<I: CompactId> RecycleBin<I> {
+    pub(crate) fn new(policy: RecyclePolicy) -> Self {
+        match policy {
+            RecyclePolicy::LowestFirst => RecycleBin::Sorted(BTreeMap::new()),
+            _ => RecycleBin::Queue(VecDeque::new()),
+        }
+    }
+
+    /// A bin holding `ids` in the order `iter` lists them. Under
+    /// `LowestFirst` they must be ascending; the other invariants are left
+    /// to `CompactIdMap::validate`.
+    pub(crate) fn from_ids(policy: RecyclePolicy, ids: impl IntoIterator<Item = I>) -> Result<Self, Error> {
+        let mut bin = Self::new(policy);
+        match &mut bin {
+            RecycleBin::Queue(queue) => queue.extend(ids),
+            RecycleBin::Sorted(sorted) => {
+                for id in ids {
+                    let index = id.to_index().ok_or(Error::InvalidId)?;
+                    match sorted.last_key_value() {
+                        Some((&last, _)) if last == index => return Err(Error::DuplicateRecycledId { index }),
+                        Some((&last, _)) if last > index => return Err(Error::UnsortedRecycleBin),
+                        _ => {}
+                    }
+                    sorted.insert(index, id);
+                }
+            }
+        }
+        Ok(bin)
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        match self {
+            RecycleBin::Queue(queue) => queue.len(),
+            RecycleBin::Sorted(sorted) => sorted.len(),
+        }
+    }
+
+    /// The freed IDs, oldest first or, under `LowestFirst`, lowest first.
+    pub(crate) fn iter(&self) -> Box<dyn Iterator<Item = &I> + '_> {
+        match self {
+            RecycleBin::Queue(queue) => Box::new(queue.iter()),
+            RecycleBin::Sorted(sorted) => Box::new(sorted.values()),
+        }
+    }
+
+    pub(crate) fn retain<F: FnMut(&I) -> bool>(&mut self, mut f: F) {
+        match self {
+            RecycleBin::Queue(queue) => queue.retain(|id| f(id)),
+            RecycleBin::Sorted(sorted) => sorted.retain(|_, id| f(id)),
+        }
+    }
+
+    pub(crate) fn clear(&mut self) {
+        match self {
+            RecycleBin::Queue(queue) => queue.clear(),
+            RecycleBin::Sorted(sorted) => sorted.clear(),
+        }
+    }
+
+    pub(crate) fn shrink_to_fit(&mut self) {
+        if let RecycleBin::Queue(queue) = self {
+            queue.shrink_to_fit();
+        }
+    }
+}
+
+/// Written as a plain list, in the order `iter` gives.
+impl<I: CompactId + Serialize> Serialize for RecycleBin<I> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_seq(self.iter())
+    }
+}
+
+impl RecyclePolicy {
+    /// The freed ID `take` would return.
+    pub(crate) fn peek<I: CompactId>(self, recycle_bin: &RecycleBin<I>) -> Option<I> {
+        match (self, recycle_bin) {
+            (RecyclePolicy::Never, _) => None,
+            (RecyclePolicy::Lifo, RecycleBin::Queue(queue)) => queue.back().copied(),
+            (_, RecycleBin::Queue(queue)) => queue.front().copied(),
+            (_, RecycleBin::Sorted(sorted)) => sorted.first_key_value().map(|(_, &id)| id),
+        }
+    }
+
+    /// Takes the next freed ID to reuse, if any.
+    pub(crate) fn take<I: CompactId>(self, recycle_bin: &mut RecycleBin<I>) -> Option<I> {
+        match (self, recycle_bin) {
+            (RecyclePolicy::Never, _) => None,
+            (RecyclePolicy::Lifo, RecycleBin::Queue(queue)) => queue.pop_back(),
+            (_, RecycleBin::Queue(queue)) => queue.pop_front(),
+            (_, RecycleBin::Sorted(sorted)) => sorted.pop_first().map(|(_, id)| id),
+        }
+    }
+
+    /// Records `id` as freed.
+    pub(crate) fn recycle<I: CompactId>(self, recycle_bin: &mut RecycleBin<I>, id: I) {
+        match (self, recycle_bin) {
+            (RecyclePolicy::Never, _) => {}
+            (_, RecycleBin::Queue(queue)) => queue.push_back(id),
+            (_, RecycleBin::Sorted(sorted)) => {
+                sorted.insert(id.to_index().expect("recycled IDs have a slot"), id);
+            }
+        }
+    }
+
+    /// Puts back an ID just returned by `take`.
+    pub(crate) fn untake<I: CompactId>(self, recycle_bin: &mut RecycleBin<I>, id: I) {
+        match (self, recycle_bin) {
+            (RecyclePolicy::Never, _) => {}
+            (RecyclePolicy::Lifo, RecycleBin::Queue(queue)) => queue.push_back(id),
+            (_, RecycleBin::Queue(queue)) => queue.push_front(id),
+            (_, RecycleBin::Sorted(sorted)) => {
+                sorted.insert(id.to_index().expect("recycled IDs have a slot"), id);
+            }
+        }
+    }
+
+    /// Takes back an ID just passed to `recycle`.
+    pub(crate) fn unrecycle<I: CompactId>(self, recycle_bin: &mut RecycleBin<I>, id: I) {
+        match (self, recycle_bin) {
+            (RecyclePolicy::Never, _) => {}
+            (_, RecycleBin::Queue(queue)) => {
+                queue.pop_back();
+            }
+            (_, RecycleBin::Sorted(sorted)) => {
+                sorted.remove(&id.to_index().expect("recycled IDs have a slot"));
+            }
+        }
+    }
+}
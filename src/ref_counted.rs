@@ -0,0 +1,210 @@
+use std::{borrow::Borrow, hash::Hash};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{CompactId, CompactIdBiMap, Entry, Error, ID};
+
+/// A `CompactIdBiMap` that counts how many times each key was interned.
+/// Every `get_or_insert` takes a reference and every `release` gives one
+/// back; the ID is only recycled once its last reference is released.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(
+    try_from = "RefCountedIdBiMapRepr<K, I>",
+    bound(
+        serialize = "CompactIdBiMap<K, I>: Serialize",
+        deserialize = "CompactIdBiMap<K, I>: Deserialize<'de>, K: Hash + Eq, I: CompactId + Eq"
+    )
+)]
+pub struct RefCountedIdBiMap<K, I = ID>
+    where K: Eq + Hash
+{
+    map: CompactIdBiMap<K, I>,
+    /// The reference count of each slot; zero for vacant slots.
+    counts: Vec<usize>,
+}
+
+#[derive(Deserialize)]
+#[serde(bound(deserialize = "CompactIdBiMap<K, I>: Deserialize<'de>"))]
+struct RefCountedIdBiMapRepr<K, I>
+    where K: Eq + Hash
+{
+    map: CompactIdBiMap<K, I>,
+    counts: Vec<usize>,
+}
+
+impl<K, I> TryFrom<RefCountedIdBiMapRepr<K, I>> for RefCountedIdBiMap<K, I> where
+K: Hash + Eq,
+I: CompactId + Eq
+{
+    type Error = Error;
+
+    fn try_from(repr: RefCountedIdBiMapRepr<K, I>) -> Result<Self, Error> {
+        let RefCountedIdBiMapRepr { map, counts } = repr;
+        let refcounted = Self { map, counts };
+        refcounted.validate()?;
+        Ok(refcounted)
+    }
+}
+
+impl<K, I> Default for RefCountedIdBiMap<K, I> where
+K: Hash + Eq,
+I: CompactId + Eq
+{
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<K, I> RefCountedIdBiMap<K, I> where
+K: Hash + Eq,
+I: CompactId + Eq
+{
+    pub fn new() -> Self {
+        Self {
+            map: CompactIdBiMap::new(),
+            counts: Vec::new(),
+        }
+    }
+
+    fn count_mut(&mut self, id: I) -> &mut usize {
+        let index = id.to_index().expect("live IDs have a slot");
+        if index >= self.counts.len() {
+            self.counts.resize(index + 1, 0);
+        }
+        &mut self.counts[index]
+    }
+
+    /// Returns the ID of `k`, inserting it first if needed, and takes a
+    /// reference to it.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `k` is new and no ID is left; see `try_get_or_insert`.
+    pub fn get_or_insert(&mut self, k: K) -> I {
+        self.try_get_or_insert(k).unwrap_or_else(|err| panic!("{err}"))
+    }
+
+    /// Like `get_or_insert`, but returns an error instead of panicking if
+    /// `k` is new and no ID is left.
+    pub fn try_get_or_insert(&mut self, k: K) -> Result<I, Error> {
+        let id = match self.map.entry(k) {
+            Entry::Occupied(entry) => entry.id(),
+            Entry::Vacant(entry) => entry.try_insert()?,
+        };
+        *self.count_mut(id) += 1;
+        Ok(id)
+    }
+
+    /// Takes another reference to a live ID, returning the new count, or
+    /// `None` if `id` is not live.
+    pub fn acquire(&mut self, id: I) -> Option<usize> {
+        self.map.get_key(id)?;
+        let count = self.count_mut(id);
+        *count += 1;
+        Some(*count)
+    }
+
+    /// Gives back a reference to `id`, returning the number of references
+    /// left, or `None` if `id` is not live. When none are left the key is
+    /// removed and the ID recycled.
+    pub fn release(&mut self, id: I) -> Option<usize> {
+        self.map.get_key(id)?;
+        let count = self.count_mut(id);
+        *count -= 1;
+        let left = *count;
+        if left == 0 {
+            self.map.remove_id(id);
+        }
+        Some(left)
+    }
+
+    /// The number of references to `id`; zero if it is not live.
+    pub fn ref_count(&self, id: I) -> usize {
+        if !self.map.contains_id(id) {
+            return 0;
+        }
+        id.to_index()
+            .and_then(|index| self.counts.get(index))
+            .copied()
+            .unwrap_or(0)
+    }
+
+    pub fn get<Q>(&self, key: &Q) -> Option<I>
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Hash + Eq
+    {
+        self.map.get(key)
+    }
+
+    pub fn get_key(&self, id: I) -> Option<&K> {
+        self.map.get_key(id)
+    }
+
+    /// Checks the invariants of the underlying bimap and that exactly the
+    /// live IDs have a non-zero reference count.
+    pub fn validate(&self) -> Result<(), Error> {
+        self.map.validate()?;
+        let mut live_ids = self.map.ids().filter_map(I::to_index).peekable();
+        let slots = self.map.ids().last().and_then(I::to_index).map_or(0, |last| last + 1);
+        for index in 0..slots.max(self.counts.len()) {
+            let live = live_ids.next_if_eq(&index).is_some();
+            let count = self.counts.get(index).copied().unwrap_or(0);
+            if live != (count > 0) {
+                return Err(Error::InvalidRefCount { index });
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test_ref_counted {
+    use super::*;
+    use crate::GenerationalId;
+
+    #[test]
+    fn test_release() {
+        let mut symbols = RefCountedIdBiMap::<String>::new();
+        let a = symbols.get_or_insert(String::from("a"));
+        assert_eq!(a, symbols.get_or_insert(String::from("a")));
+        let b = symbols.get_or_insert(String::from("b"));
+        assert_eq!(2, symbols.ref_count(a));
+        assert_eq!(Some(1), symbols.release(a));
+        assert_eq!(Some(&String::from("a")), symbols.get_key(a));
+        assert_eq!(Some(0), symbols.release(a));
+        assert_eq!(None, symbols.get("a"));
+        assert_eq!(None, symbols.release(a));
+        assert_eq!(0, symbols.ref_count(a));
+        assert_eq!(Some(2), symbols.acquire(b));
+        assert_eq!(a, symbols.get_or_insert(String::from("c")));
+        assert_eq!(1, symbols.ref_count(a));
+    }
+
+    #[test]
+    fn test_serialized_counts() {
+        let mut symbols = RefCountedIdBiMap::<String, u32>::new();
+        symbols.get_or_insert(String::from("x"));
+        symbols.get_or_insert(String::from("x"));
+        symbols.get_or_insert(String::from("y"));
+        let json = serde_json::to_string(&symbols).unwrap();
+        let loaded: RefCountedIdBiMap<String, u32> = serde_json::from_str(&json).unwrap();
+        assert_eq!(2, loaded.ref_count(0));
+        assert_eq!(1, loaded.ref_count(1));
+
+        let zero = json.replace("[2,1]", "[2,0]");
+        assert!(serde_json::from_str::<RefCountedIdBiMap<String, u32>>(&zero).is_err());
+    }
+
+    #[test]
+    fn test_stale_ref_count() {
+        let mut symbols = RefCountedIdBiMap::<&str, GenerationalId<u8>>::new();
+        let a = symbols.get_or_insert("a");
+        symbols.release(a);
+        let b = symbols.get_or_insert("b");
+        symbols.get_or_insert("b");
+        assert_eq!(a.index(), b.index());
+        assert_eq!(0, symbols.ref_count(a));
+        assert_eq!(2, symbols.ref_count(b));
+    }
+}
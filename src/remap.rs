@@ -0,0 +1,39 @@
+use crate::CompactId;
+
+/// Old ID to new ID table returned by `compact`, for renumbering IDs held
+/// outside the map.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct IdRemap<I> {
+    /// The generation of each old slot and its new ID, or `None` for slots
+    /// that were free.
+    new_ids: Vec<Option<(u32, I)>>,
+}
+
+impl<I: CompactId> IdRemap<I> {
+    pub(crate) fn new(new_ids: Vec<Option<(u32, I)>>) -> Self {
+        Self { new_ids }
+    }
+
+    /// The new ID of `old`, or `None` if `old` was not live (including IDs
+    /// from an earlier generation of a live slot).
+    pub fn get(&self, old: I) -> Option<I> {
+        let (generation, new) = (*self.new_ids.get(old.to_index()?)?)?;
+        (generation == old.generation()).then_some(new)
+    }
+
+    /// Iterates over `(old, new)` pairs in ascending order.
+    pub fn iter(&self) -> impl DoubleEndedIterator<Item = (I, I)> + '_ {
+        self.new_ids.iter().enumerate().filter_map(|(index, new)| {
+            let (generation, new) = (*new)?;
+            let old = I::from_index(index).expect("old IDs were valid");
+            Some((old.with_generation(generation), new))
+        })
+    }
+
+    /// Whether no live ID changed.
+    pub fn is_identity(&self) -> bool {
+        self.iter().all(|(old, new)| {
+            (old.to_index(), old.generation()) == (new.to_index(), new.generation())
+        })
+    }
+}
@@ -0,0 +1,330 @@
+use std::{
+    hash::{BuildHasher, Hash},
+    marker::PhantomData,
+};
+
+use hashbrown::HashMap;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    iter::live_id, CompactId, CompactIdBiMap, CompactIdMap, CompactIdValueMap, Iter, IterMut,
+    RefCountedIdBiMap, Slot,
+};
+
+/// A map handing out IDs, which secondary maps can check their IDs against.
+pub trait ContainsId<I> {
+    fn contains_id(&self, id: I) -> bool;
+}
+
+impl<I: CompactId, K> ContainsId<I> for CompactIdMap<I, K> {
+    fn contains_id(&self, id: I) -> bool {
+        CompactIdMap::contains_id(self, id)
+    }
+}
+
+impl<K, I, S, T> ContainsId<I> for CompactIdBiMap<K, I, S, T> where
+K: Hash + Eq,
+I: CompactId + Eq,
+S: BuildHasher,
+T: Slot<K>
+{
+    fn contains_id(&self, id: I) -> bool {
+        CompactIdBiMap::contains_id(self, id)
+    }
+}
+
+impl<K, V, I, S> ContainsId<I> for CompactIdValueMap<K, V, I, S> where
+K: Hash + Eq,
+I: CompactId + Eq,
+S: BuildHasher
+{
+    fn contains_id(&self, id: I) -> bool {
+        CompactIdBiMap::contains_id(self, id)
+    }
+}
+
+impl<K, I> ContainsId<I> for RefCountedIdBiMap<K, I> where
+K: Hash + Eq,
+I: CompactId + Eq
+{
+    fn contains_id(&self, id: I) -> bool {
+        self.get_key(id).is_some()
+    }
+}
+
+/// Values attached to IDs of a primary map, stored in a `Vec` indexed like
+/// the primary's slots. Best for attributes most IDs have.
+///
+/// The map does not see removals from the primary. With `GenerationalId`s it
+/// records the generation of each value, so a value is never returned for an
+/// ID reusing its slot, and `retain_live` drops values whose ID was removed
+/// even if the slot is live again. Plain IDs carry no generation, so a slot
+/// reused by the primary cannot be told apart: call `retain_live` after
+/// every removal, before the freed IDs are reused.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct SecondaryIdMap<I, V> {
+    values: Vec<Option<V>>,
+    /// The generation of the ID each value belongs to, for generational IDs.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    generations: Vec<u32>,
+    #[serde(skip)]
+    _ids: PhantomData<fn() -> I>,
+}
+
+impl<I, V> Default for SecondaryIdMap<I, V> where
+I: CompactId
+{
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<I, V> SecondaryIdMap<I, V> where
+I: CompactId
+{
+    pub fn new() -> Self {
+        Self {
+            values: Vec::new(),
+            generations: Vec::new(),
+            _ids: PhantomData,
+        }
+    }
+
+    /// The slot index of `id`, if its slot holds a value for this very ID
+    /// rather than for an earlier generation of it.
+    fn index_of(&self, id: I) -> Option<usize> {
+        let index = id.to_index()?;
+        let generation = self.generations.get(index).copied().unwrap_or(0);
+        (id.generation() == generation).then_some(index)
+    }
+
+    /// Attaches `v` to `id`, returning the value it replaces. A value left
+    /// for an earlier generation of the slot is dropped instead. Generations
+    /// only go up, so an `id` older than the one the slot holds a value for
+    /// is stale: `v` is dropped and the slot left alone.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `id` has no slot, e.g. a negative integer.
+    pub fn insert(&mut self, id: I, v: V) -> Option<V> {
+        let index = id.to_index().expect("secondary map IDs have a slot");
+        if index >= self.values.len() {
+            self.values.resize_with(index + 1, || None);
+        }
+        if I::GENERATIONAL {
+            if index >= self.generations.len() {
+                self.generations.resize(index + 1, 0);
+            }
+            let generation = &mut self.generations[index];
+            if id.generation() < *generation {
+                return None;
+            }
+            if std::mem::replace(generation, id.generation()) != id.generation() {
+                self.values[index] = None;
+            }
+        }
+        self.values[index].replace(v)
+    }
+
+    pub fn get(&self, id: I) -> Option<&V> {
+        self.values.get(self.index_of(id)?)?.as_ref()
+    }
+
+    pub fn get_mut(&mut self, id: I) -> Option<&mut V> {
+        let index = self.index_of(id)?;
+        self.values.get_mut(index)?.as_mut()
+    }
+
+    pub fn contains_id(&self, id: I) -> bool {
+        self.get(id).is_some()
+    }
+
+    pub fn remove(&mut self, id: I) -> Option<V> {
+        let index = self.index_of(id)?;
+        self.values.get_mut(index)?.take()
+    }
+
+    /// Iterates over `(id, &value)` pairs in ascending ID order.
+    pub fn iter(&self) -> Iter<'_, I, V> {
+        Iter::new(&self.values, &self.generations)
+    }
+
+    /// Iterates over `(id, &mut value)` pairs in ascending ID order.
+    pub fn iter_mut(&mut self) -> IterMut<'_, I, V> {
+        IterMut::new(&mut self.values, &self.generations)
+    }
+
+    /// Keeps only the values for which `f` returns `true`.
+    pub fn retain<F: FnMut(I, &mut V) -> bool>(&mut self, mut f: F) {
+        for (index, slot) in self.values.iter_mut().enumerate() {
+            if let Some(v) = slot {
+                if !f(live_id(index, &self.generations), v) {
+                    *slot = None;
+                }
+            }
+        }
+    }
+
+    /// Drops the values of IDs that are no longer in `primary`. IDs are
+    /// compared whole, so with `GenerationalId`s this also drops values
+    /// whose slot the primary has reused since.
+    pub fn retain_live<P: ContainsId<I>>(&mut self, primary: &P) {
+        self.retain(|id, _| primary.contains_id(id));
+    }
+}
+
+impl<'a, I, V> IntoIterator for &'a SecondaryIdMap<I, V> where
+I: CompactId
+{
+    type Item = (I, &'a V);
+    type IntoIter = Iter<'a, I, V>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.iter()
+    }
+}
+
+/// Values attached to IDs of a primary map, stored in a hash map. Best for
+/// attributes few IDs have.
+///
+/// Values are keyed by the whole ID, so with `GenerationalId`s an ID reusing
+/// a slot never sees the value of an earlier one. Like `SecondaryIdMap`,
+/// call `retain_live` to drop the values of IDs removed from the primary.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(bound(
+    serialize = "I: Serialize + Hash + Eq, V: Serialize",
+    deserialize = "I: Deserialize<'de> + Hash + Eq, V: Deserialize<'de>"
+))]
+pub struct SparseSecondaryIdMap<I, V> {
+    values: HashMap<I, V>,
+}
+
+impl<I, V> Default for SparseSecondaryIdMap<I, V> where
+I: CompactId + Hash + Eq
+{
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<I, V> SparseSecondaryIdMap<I, V> where
+I: CompactId + Hash + Eq
+{
+    pub fn new() -> Self {
+        Self { values: HashMap::new() }
+    }
+
+    /// Attaches `v` to `id`, returning the value it replaces.
+    pub fn insert(&mut self, id: I, v: V) -> Option<V> {
+        self.values.insert(id, v)
+    }
+
+    pub fn get(&self, id: I) -> Option<&V> {
+        self.values.get(&id)
+    }
+
+    pub fn get_mut(&mut self, id: I) -> Option<&mut V> {
+        self.values.get_mut(&id)
+    }
+
+    pub fn contains_id(&self, id: I) -> bool {
+        self.values.contains_key(&id)
+    }
+
+    pub fn remove(&mut self, id: I) -> Option<V> {
+        self.values.remove(&id)
+    }
+
+    /// Iterates over `(id, &value)` pairs in arbitrary order.
+    pub fn iter(&self) -> impl Iterator<Item = (I, &V)> + '_ {
+        self.values.iter().map(|(&id, v)| (id, v))
+    }
+
+    /// Iterates over `(id, &mut value)` pairs in arbitrary order.
+    pub fn iter_mut(&mut self) -> impl Iterator<Item = (I, &mut V)> + '_ {
+        self.values.iter_mut().map(|(&id, v)| (id, v))
+    }
+
+    /// Keeps only the values for which `f` returns `true`.
+    pub fn retain<F: FnMut(I, &mut V) -> bool>(&mut self, mut f: F) {
+        self.values.retain(|&id, v| f(id, v));
+    }
+
+    /// Drops the values of IDs that are no longer in `primary`, including,
+    /// for `GenerationalId`s, IDs whose slot the primary has reused since.
+    pub fn retain_live<P: ContainsId<I>>(&mut self, primary: &P) {
+        self.retain(|id, _| primary.contains_id(id));
+    }
+}
+
+#[cfg(test)]
+mod test_secondary {
+    use super::*;
+    use crate::{GenerationalId, GenerationalIdBiMap};
+
+    #[test]
+    fn test_dense_attributes() {
+        let mut entities = CompactIdMap::<u32, &str>::new();
+        let a = entities.insert("a");
+        let b = entities.insert("b");
+        let mut positions = SecondaryIdMap::new();
+        assert_eq!(None, positions.insert(a, (0, 0)));
+        assert_eq!(None, positions.insert(b, (1, 2)));
+        positions.get_mut(a).unwrap().0 = 5;
+        assert_eq!(vec![(a, &(5, 0)), (b, &(1, 2))], positions.iter().collect::<Vec<_>>());
+
+        entities.remove_id(a);
+        positions.retain_live(&entities);
+        assert_eq!(None, positions.get(a));
+        assert_eq!(Some((1, 2)), positions.remove(b));
+        assert_eq!(0, positions.iter().count());
+    }
+
+    #[test]
+    fn test_sparse_attributes() {
+        let mut names = CompactIdBiMap::<&str, u16>::new();
+        let x = names.insert("x");
+        let y = names.insert("y");
+        let mut tags = SparseSecondaryIdMap::new();
+        tags.insert(y, "rare");
+        assert_eq!(None, tags.get(x));
+        assert_eq!(Some(&"rare"), tags.get(y));
+
+        names.remove("y");
+        tags.retain_live(&names);
+        assert!(!tags.contains_id(y));
+    }
+
+    #[test]
+    fn test_reused_slots() {
+        let mut people = GenerationalIdBiMap::<&str, u8>::new();
+        let alice = people.insert("alice");
+        let mut age = SecondaryIdMap::new();
+        let mut nickname = SparseSecondaryIdMap::new();
+        age.insert(alice, 30);
+        nickname.insert(alice, "al");
+
+        people.remove("alice");
+        let bob = people.insert("bob");
+        assert_eq!(alice.index(), bob.index());
+        assert_eq!(None, age.get(bob));
+        assert_eq!(None, nickname.get(bob));
+        age.retain_live(&people);
+        nickname.retain_live(&people);
+        assert_eq!(None, age.get(alice));
+        assert_eq!(0, nickname.iter().count());
+
+        age.insert(alice, 31);
+        assert_eq!(None, age.insert(bob, 40));
+        assert_eq!(None, age.get(alice));
+        assert_eq!(vec![(bob, &40)], age.iter().collect::<Vec<_>>());
+        let json = serde_json::to_string(&age).unwrap();
+        let loaded: SecondaryIdMap<GenerationalId<u8>, i32> = serde_json::from_str(&json).unwrap();
+        assert_eq!(Some(&40), loaded.get(bob));
+
+        // A stale ID arriving after the live one must not evict its value.
+        assert_eq!(None, age.insert(alice, 32));
+        assert_eq!(Some(&40), age.get(bob));
+        assert_eq!(None, age.get(alice));
+    }
+}
@@ -0,0 +1,376 @@
+use std::{
+    borrow::Borrow,
+    hash::{BuildHasher, Hash},
+    ops::{Deref, DerefMut},
+};
+
+use hashbrown::{hash_table, DefaultHashBuilder};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+use crate::{
+    CompactId, CompactIdBiMap, CompactIdMap, Entry, Error, IdMapOptions, IntoIter, Iter,
+    RecyclePolicy, Slot, ID,
+};
+
+/// A `CompactIdBiMap` that also stores a value with every key, such as its
+/// frequency or source span. The value lives in the same slot as the key, so
+/// it goes away with the key and never drifts out of sync.
+///
+/// This pins the slots of the bimap to `(key, value)` pairs and adds the
+/// methods dealing with values; the rest of the bimap API, including entries
+/// that hand out the value, is available through `Deref`.
+#[derive(Clone, Debug)]
+pub struct CompactIdValueMap<K, V, I = ID, S = DefaultHashBuilder>(CompactIdBiMap<K, I, S, (K, V)>)
+    where K: Eq + Hash;
+
+impl<K, V, I, S> Default for CompactIdValueMap<K, V, I, S> where
+K: Hash + Eq,
+I: CompactId + Eq,
+S: BuildHasher + Default
+{
+    fn default() -> Self {
+        Self(CompactIdBiMap::default())
+    }
+}
+
+impl<K, V, I> CompactIdValueMap<K, V, I> where
+K: Hash + Eq,
+I: CompactId + Eq
+{
+    pub fn new() -> Self {
+        Self::with_options(IdMapOptions::new())
+    }
+
+    /// An empty map with all of the given settings.
+    pub fn with_options(options: IdMapOptions<I>) -> Self {
+        Self(CompactIdBiMap::with_options_and_hasher(options, DefaultHashBuilder::default()))
+    }
+
+    /// An empty map with room for `capacity` entries before reallocating.
+    pub fn with_capacity(capacity: usize) -> Self {
+        Self::with_options(IdMapOptions::new().capacity(capacity))
+    }
+
+    pub fn with_policy(policy: RecyclePolicy) -> Self {
+        Self::with_options(IdMapOptions::new().policy(policy))
+    }
+
+    /// A map that never hands out an ID above `max_id`, while still reusing
+    /// freed IDs below it.
+    pub fn with_max_id(max_id: I) -> Self {
+        Self::with_options(IdMapOptions::new().max_id(max_id))
+    }
+}
+
+impl<K, V, I, S> CompactIdValueMap<K, V, I, S> where
+K: Hash + Eq,
+I: CompactId + Eq,
+S: BuildHasher
+{
+    /// An empty map hashing its keys with `hash_builder`.
+    pub fn with_hasher(hash_builder: S) -> Self {
+        Self(CompactIdBiMap::with_hasher(hash_builder))
+    }
+
+    /// An empty map with room for `capacity` entries, hashing their keys
+    /// with `hash_builder`.
+    pub fn with_capacity_and_hasher(capacity: usize, hash_builder: S) -> Self {
+        Self(CompactIdBiMap::with_capacity_and_hasher(capacity, hash_builder))
+    }
+
+    /// An empty map with the given settings, hashing keys with
+    /// `hash_builder`.
+    pub fn with_options_and_hasher(options: IdMapOptions<I>, hash_builder: S) -> Self {
+        Self(CompactIdBiMap::with_options_and_hasher(options, hash_builder))
+    }
+
+    pub fn with_policy_and_hasher(policy: RecyclePolicy, hash_builder: S) -> Self {
+        Self(CompactIdBiMap::with_policy_and_hasher(policy, hash_builder))
+    }
+
+    /// A map that never hands out an ID above `max_id`, hashing keys with
+    /// `hash_builder`.
+    pub fn with_max_id_and_hasher(max_id: I, hash_builder: S) -> Self {
+        Self(CompactIdBiMap::with_max_id_and_hasher(max_id, hash_builder))
+    }
+
+    /// Indexes the keys of `map` with `hash_builder`; see
+    /// `CompactIdBiMap::from_map_with_hasher`.
+    pub fn from_map_with_hasher(map: CompactIdMap<I, (K, V)>, hash_builder: S) -> Result<Self, Error> {
+        CompactIdBiMap::from_map_with_hasher(map, hash_builder).map(Self)
+    }
+
+    pub fn into_bimap(self) -> CompactIdBiMap<K, I, S, (K, V)> {
+        self.0
+    }
+}
+
+impl<K, V, I, S> From<CompactIdBiMap<K, I, S, (K, V)>> for CompactIdValueMap<K, V, I, S> where
+K: Hash + Eq
+{
+    fn from(bimap: CompactIdBiMap<K, I, S, (K, V)>) -> Self {
+        Self(bimap)
+    }
+}
+
+impl<K, V, I, S> Deref for CompactIdValueMap<K, V, I, S> where
+K: Hash + Eq
+{
+    type Target = CompactIdBiMap<K, I, S, (K, V)>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+impl<K, V, I, S> DerefMut for CompactIdValueMap<K, V, I, S> where
+K: Hash + Eq
+{
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.0
+    }
+}
+
+impl<K, V, I, S> IntoIterator for CompactIdValueMap<K, V, I, S> where
+K: Hash + Eq,
+I: CompactId
+{
+    type Item = (I, (K, V));
+    type IntoIter = IntoIter<I, (K, V)>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.0.into_iter()
+    }
+}
+
+impl<'a, K, V, I, S> IntoIterator for &'a CompactIdValueMap<K, V, I, S> where
+K: Hash + Eq,
+I: CompactId
+{
+    type Item = (I, &'a (K, V));
+    type IntoIter = Iter<'a, I, (K, V)>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        (&self.0).into_iter()
+    }
+}
+
+/// Indexes the keys of a map of `(key, value)` pairs; see the `TryFrom`
+/// impl of `CompactIdBiMap`.
+impl<K, V, I, S> TryFrom<CompactIdMap<I, (K, V)>> for CompactIdValueMap<K, V, I, S> where
+K: Hash + Eq,
+I: CompactId + Eq,
+S: BuildHasher + Default
+{
+    type Error = Error;
+
+    fn try_from(map: CompactIdMap<I, (K, V)>) -> Result<Self, Error> {
+        CompactIdBiMap::try_from(map).map(Self)
+    }
+}
+
+/// Serialized as the underlying `CompactIdMap` of `(key, value)` pairs; the
+/// reverse index is rebuilt on load, with `S::default()` as the hasher. To
+/// load with another hasher, use a `CompactIdBiMapSeed` and convert.
+impl<K, V, I, S> Serialize for CompactIdValueMap<K, V, I, S> where
+K: Hash + Eq + Serialize,
+V: Serialize,
+I: CompactId + Serialize
+{
+    fn serialize<Ser: Serializer>(&self, serializer: Ser) -> Result<Ser::Ok, Ser::Error> {
+        self.0.serialize(serializer)
+    }
+}
+
+impl<'de, K, V, I, S> Deserialize<'de> for CompactIdValueMap<K, V, I, S> where
+K: Hash + Eq + Deserialize<'de>,
+V: Deserialize<'de>,
+I: CompactId + Eq + Deserialize<'de>,
+S: BuildHasher + Default
+{
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        CompactIdBiMap::deserialize(deserializer).map(Self)
+    }
+}
+
+impl<K, V> Slot<K> for (K, V) {
+    fn key(&self) -> &K {
+        &self.0
+    }
+
+    fn key_mut(&mut self) -> &mut K {
+        &mut self.0
+    }
+}
+
+impl<K, V, I, S> CompactIdValueMap<K, V, I, S> where
+K: Hash + Eq,
+I: CompactId + Eq,
+S: BuildHasher
+{
+    /// Interns `k` with the value `v`, returning its ID. If `k` already has
+    /// an ID, its value is replaced and the old one returned as well.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `k` is new and no ID is left; see `try_insert`.
+    pub fn insert(&mut self, k: K, v: V) -> (I, Option<V>) {
+        self.try_insert(k, v).unwrap_or_else(|err| panic!("{err}"))
+    }
+
+    /// Like `insert`, but returns an error instead of panicking if `k` is new
+    /// and no ID is left.
+    pub fn try_insert(&mut self, k: K, v: V) -> Result<(I, Option<V>), Error> {
+        match self.entry(k) {
+            Entry::Occupied(mut entry) => Ok((entry.id(), Some(entry.insert(v)))),
+            Entry::Vacant(entry) => entry.try_insert(v).map(|(id, _)| (id, None)),
+        }
+    }
+
+    /// Returns the ID of `k` and its value, first interning it with the
+    /// value returned by `f` if needed.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `k` is new and no ID is left.
+    pub fn get_or_insert_with<F: FnOnce() -> V>(&mut self, k: K, f: F) -> (I, &mut V) {
+        self.entry(k).or_insert_with(|_| f())
+    }
+
+    /// Like `get_or_insert_with`, but only builds an owned key when `key` is
+    /// not interned yet. The key is hashed once either way.
+    pub fn get_or_insert_borrowed_with<Q, F>(&mut self, key: &Q, f: F) -> (I, &mut V)
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Hash + Eq + ToOwned<Owned = K>,
+        F: FnOnce() -> V
+    {
+        let (id, map) = match self.0.lookup(key) {
+            (hash_table::Entry::Occupied(index), map) => (*index.get(), map),
+            (hash_table::Entry::Vacant(index), map) => {
+                let id = map.insert((key.to_owned(), f()));
+                index.insert(id);
+                (id, map)
+            }
+        };
+        (id, &mut map.get_mut(id).expect("just looked up").1)
+    }
+
+    /// The ID of `key` and its value.
+    pub fn get_with_value<Q>(&self, key: &Q) -> Option<(I, &V)>
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Hash + Eq
+    {
+        let id = self.get(key)?;
+        Some((id, self.get_value(id)?))
+    }
+
+    pub fn get_value(&self, id: I) -> Option<&V> {
+        self.0.as_map().get(id).map(|(_, v)| v)
+    }
+
+    pub fn get_value_mut(&mut self, id: I) -> Option<&mut V> {
+        self.0.map_mut().get_mut(id).map(|(_, v)| v)
+    }
+
+    /// Iterates over the values in ascending ID order.
+    pub fn values(&self) -> impl DoubleEndedIterator<Item = &V> + '_ {
+        self.0.as_map().values().map(|(_, v)| v)
+    }
+
+    /// Iterates over the values in ascending ID order, mutably.
+    pub fn values_mut(&mut self) -> impl DoubleEndedIterator<Item = &mut V> + '_ {
+        self.iter_mut().map(|(_, _, v)| v)
+    }
+
+    /// Iterates over `(id, &key, &mut value)` triples in ascending ID order.
+    /// Keys stay immutable, as changing them would break the reverse index.
+    pub fn iter_mut(&mut self) -> impl DoubleEndedIterator<Item = (I, &K, &mut V)> + '_ {
+        self.0.map_mut().iter_mut().map(|(id, (k, v))| (id, &*k, v))
+    }
+}
+
+#[cfg(test)]
+mod test_value_map {
+    use super::*;
+
+    #[test]
+    fn test_values() {
+        let mut words = CompactIdValueMap::<String, usize, u32>::new();
+        for word in "a rose is a rose is a rose".split(' ') {
+            *words.get_or_insert_borrowed_with(word, || 0).1 += 1;
+        }
+        let (rose, &count) = words.get_with_value("rose").unwrap();
+        assert_eq!(3, count);
+        assert_eq!(Some(&String::from("rose")), words.get_key(rose));
+        *words.get_value_mut(rose).unwrap() = 7;
+        assert_eq!(Some(&7), words.get_value(rose));
+        assert_eq!((rose, Some(7)), words.insert(String::from("rose"), 1));
+
+        let (a, is) = (words.get("a").unwrap(), words.get("is").unwrap());
+        assert_eq!(Some((a, (String::from("a"), 3))), words.remove_entry("a"));
+        assert_eq!(Some((String::from("is"), 2)), words.remove_id(is));
+        assert_eq!(None, words.get_with_value("is"));
+        assert_eq!(vec![(rose, &(String::from("rose"), 1))], words.iter().collect::<Vec<_>>());
+        assert_eq!((is, None), words.insert(String::from("new"), 0));
+    }
+
+    #[test]
+    fn test_value_entries() {
+        let mut spans = CompactIdValueMap::<&str, (u32, u32), u8>::new();
+        let (f, span) = spans.entry("f").or_insert((0, 4));
+        span.1 = 5;
+        assert_eq!((f, &mut (0, 5)), spans.entry("f").or_insert((9, 9)));
+        match spans.entry("g") {
+            Entry::Vacant(entry) => assert_eq!((1, &mut (6, 9)), entry.insert((6, 9))),
+            Entry::Occupied(_) => unreachable!(),
+        }
+        match spans.entry("f") {
+            Entry::Occupied(entry) => assert_eq!(("f", (0, 5)), entry.remove()),
+            Entry::Vacant(_) => unreachable!(),
+        }
+
+        let checkpoint = spans.checkpoint();
+        spans.replace_key(1, "h").unwrap();
+        spans.insert("i", (10, 12));
+        spans.rollback(checkpoint);
+        assert_eq!(vec![(1, &("g", (6, 9)))], spans.iter().collect::<Vec<_>>());
+
+        let remap = spans.compact();
+        assert_eq!(Some(0), remap.get(1));
+        assert_eq!(Some((0, &(6, 9))), spans.get_with_value("g"));
+        spans.insert("j", (13, 14));
+        assert_eq!(Ok(()), spans.validate());
+        assert_eq!(vec![(0, ("g", (6, 9))), (1, ("j", (13, 14)))], spans.drain().collect::<Vec<_>>());
+        assert!(spans.is_empty());
+    }
+
+    #[test]
+    fn test_value_rollback() {
+        let mut ages = CompactIdValueMap::<&str, u32>::new();
+        let (a, _) = ages.insert("a", 1);
+        let checkpoint = ages.checkpoint();
+        assert_eq!((a, Some(1)), ages.insert("a", 2));
+        match ages.entry("a") {
+            Entry::Occupied(mut entry) => assert_eq!(2, entry.insert(3)),
+            Entry::Vacant(_) => unreachable!(),
+        }
+        ages.rollback(checkpoint);
+        assert_eq!(Some(&1), ages.get_value(a));
+    }
+
+    #[test]
+    fn test_value_map_serde() {
+        let mut spans = CompactIdValueMap::<&str, (u32, u32)>::new();
+        spans.insert("f", (0, 4));
+        spans.insert("g", (5, 9));
+        let json = serde_json::to_string(&spans).unwrap();
+        let loaded: CompactIdValueMap<&str, (u32, u32)> = serde_json::from_str(&json).unwrap();
+        assert_eq!(Some((1, &(5, 9))), loaded.get_with_value("g"));
+        assert_eq!(Ok(()), loaded.validate());
+
+        let duplicate = r#"{"keys":[["f",[0,4]],["f",[5,9]]]}"#;
+        assert!(serde_json::from_str::<CompactIdValueMap<&str, (u32, u32)>>(duplicate).is_err());
+    }
+}